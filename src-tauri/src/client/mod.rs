@@ -1,2 +1,2 @@
+#[allow(clippy::module_inception)]
 pub mod client;
-pub mod proto;
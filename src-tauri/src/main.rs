@@ -1,27 +1,140 @@
 mod client;
 mod server;
-mod structs;
 mod utils;
 
 use utils::handle_exit;
 
 use self::client::client::join_chat;
-use self::server::chat::create_chat;
+use self::server::chat::{
+    approve_join, ban_member, create_chat, deny_join, get_queue_stats, kick_member,
+};
 
 #[tauri::command]
 fn exit_app() {
     std::process::exit(0);
 }
 
+#[tauri::command]
+async fn get_safety_number(is_host: bool, room_id: String) -> Result<String, String> {
+    if is_host {
+        tempchat_core::server::chat::safety_number(&room_id).await
+    } else {
+        tempchat_core::client::client::safety_number(&room_id).await
+    }
+}
+
+#[tauri::command]
+async fn send_direct_message(
+    is_host: bool,
+    room_id: String,
+    recipient: String,
+    content: String,
+) -> Result<(), String> {
+    if is_host {
+        tempchat_core::server::chat::direct(&room_id, &recipient, content).await
+    } else {
+        tempchat_core::client::client::direct(&room_id, &recipient, content).await
+    }
+}
+
+#[tauri::command]
+async fn send_file(
+    is_host: bool,
+    room_id: String,
+    name: String,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    if is_host {
+        tempchat_core::server::chat::share(&room_id, name, data).await
+    } else {
+        tempchat_core::client::client::share(&room_id, name, data).await
+    }
+}
+
+#[tauri::command]
+async fn accept_file(is_host: bool, room_id: String, file_id: String) -> Result<(), String> {
+    if is_host {
+        tempchat_core::server::chat::accept(&room_id, &file_id).await
+    } else {
+        tempchat_core::client::client::accept(&room_id, &file_id).await
+    }
+}
+
+#[tauri::command]
+async fn update_message(
+    is_host: bool,
+    room_id: String,
+    action: tempchat_core::structs::MessageAction,
+) -> Result<(), String> {
+    if is_host {
+        tempchat_core::server::chat::update(&room_id, action).await
+    } else {
+        tempchat_core::client::client::update(&room_id, action).await
+    }
+}
+
+#[tauri::command]
+async fn set_presence(
+    is_host: bool,
+    room_id: String,
+    state: tempchat_core::structs::PresenceState,
+) -> Result<(), String> {
+    if is_host {
+        tempchat_core::server::chat::set_presence(&room_id, state).await
+    } else {
+        tempchat_core::client::client::set_presence(&room_id, state).await
+    }
+}
+
+#[tauri::command]
+async fn get_presence(
+    is_host: bool,
+    room_id: String,
+) -> Result<Vec<tempchat_core::structs::Presence>, String> {
+    if is_host {
+        tempchat_core::server::chat::presence(&room_id).await
+    } else {
+        tempchat_core::client::client::presence(&room_id).await
+    }
+}
+
+#[tauri::command]
+async fn get_members(
+    is_host: bool,
+    room_id: String,
+) -> Result<Vec<tempchat_core::structs::Member>, String> {
+    if is_host {
+        tempchat_core::server::chat::members(&room_id).await
+    } else {
+        tempchat_core::client::client::members(&room_id).await
+    }
+}
+
 fn main() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![create_chat, join_chat, exit_app])
-        .on_window_event(|event| match event.event() {
-            tauri::WindowEvent::CloseRequested { .. } => {
-                handle_exit(event);
+        .invoke_handler(tauri::generate_handler![
+            create_chat,
+            join_chat,
+            approve_join,
+            deny_join,
+            kick_member,
+            ban_member,
+            get_safety_number,
+            send_direct_message,
+            send_file,
+            accept_file,
+            update_message,
+            set_presence,
+            get_presence,
+            get_members,
+            get_queue_stats,
+            exit_app
+        ])
+        .on_window_event(|event| {
+            if let tauri::WindowEvent::CloseRequested { .. } = event.event() {
+                handle_exit();
                 exit_app();
             }
-            _ => {}
         })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
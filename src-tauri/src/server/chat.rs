@@ -1,140 +1,119 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::server::proto::{ChatData, RecvData};
-use crate::server::socket::handle::{
-    chat_shutdown, handle_connection, handle_user_message, CHAT_DATA,
-};
-use crate::server::socket::handle::{close_client, handle_message};
-use crate::structs::UserMessage;
-use crate::utils;
-use aes_siv::{
-    aead::{KeyInit, OsRng},
-    Aes256SivAead,
-};
-use futures_util::StreamExt;
-use localtunnel_client::{open_tunnel, ClientConfig};
-use nanoid::nanoid;
-use rand::Rng;
+use crate::utils::WindowEvents;
 use tauri::{command, Window};
-use tokio::sync::mpsc;
-use tokio::{net::TcpListener, sync::broadcast};
-
-async fn handle_channel_message(
-    mut rx: mpsc::UnboundedReceiver<(RecvData, String)>,
-    window: Window,
-) {
-    loop {
-        let data = rx.recv().await;
-        if let Some((message, uid)) = data {
-            if let Err(err) = handle_message(&message, &window, &uid).await {
-                close_client(&uid).await;
-                return;
-            }
-        }
-    }
-}
+use tempchat_core::files::DEFAULT_MAX_FILE_SIZE;
+use tempchat_core::server::chat::{approve, deny, host_chat, kick, queues, HostOptions};
+use tempchat_core::server::outbox::{QueueStats, SlowConsumer};
+use tempchat_core::server::proto::Transport;
+use tempchat_core::server::socket::handle::{chat_shutdown, handle_user_message};
+use tempchat_core::structs::{Heartbeat, RoomHandle, UserMessage};
+use tempchat_core::utils::{self, KdfStrength};
 
 #[command]
+#[allow(clippy::too_many_arguments)]
 pub async fn create_chat(
     username: String,
     user_limit: i32,
     password: String,
+    kdf_strength: Option<KdfStrength>,
+    transport: Option<Transport>,
+    share_history: Option<bool>,
+    message_ttl: Option<u64>,
+    max_lifetime: Option<u64>,
+    idle_timeout: Option<u64>,
+    require_approval: Option<bool>,
+    max_file_size: Option<u64>,
+    heartbeat: Option<Heartbeat>,
+    slow_consumer: Option<SlowConsumer>,
     window: Window,
-) -> Result<String, String> {
-    let port = rand::thread_rng().gen_range(10_000..=20_000);
-    let addr = format!("127.0.0.1:{}", port);
-    let listener = TcpListener::bind(&addr)
-        .await
-        .map_err(|_| format!("Unable to bind to port {}", port))?;
+) -> Result<RoomHandle, String> {
+    let identity = utils::load_identity(window.app_handle().path_resolver().app_data_dir());
+    let (room_handle, room) = host_chat(
+        HostOptions {
+            username,
+            user_limit,
+            password,
+            kdf_strength: kdf_strength.unwrap_or_default(),
+            transport: transport.unwrap_or_default(),
+            share_history: share_history.unwrap_or(false),
+            message_ttl: message_ttl.filter(|ttl| *ttl > 0),
+            max_lifetime: max_lifetime.filter(|secs| *secs > 0),
+            idle_timeout: idle_timeout.filter(|secs| *secs > 0),
+            require_approval: require_approval.unwrap_or(false),
+            max_file_size: max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+            heartbeat: heartbeat.unwrap_or_default(),
+            slow_consumer: slow_consumer.unwrap_or_default(),
+            tunnel_provider: None,
+        },
+        identity,
+        Arc::new(WindowEvents(window.clone())),
+    )
+    .await?;
+    let mut closed_rx = room.shutdown.subscribe();
 
-    let key = Aes256SivAead::generate_key(&mut OsRng);
-    let cipher = Aes256SivAead::new(&key);
-
-    *CHAT_DATA.lock().await = ChatData {
-        key_cipher: cipher,
-        key: key.to_vec(),
-        user_limit: user_limit,
-        host_username: username,
-        peer_map: HashMap::new(),
-    };
-
-    let (notify_shutdown, _) = broadcast::channel(1);
-    let alphabet: [char; 36] = [
-        '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h',
-        'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
-    ];
-    let chat_id = nanoid!(16, &alphabet);
-    let config = ClientConfig {
-        server: Some("https://loca.lt".into()),
-        subdomain: Some(chat_id),
-        local_host: Some("127.0.0.1".into()),
-        local_port: port,
-        shutdown_signal: notify_shutdown.clone(),
-        max_conn: user_limit.clone() as u8 + 5, //Allow more socket connections
-        credential: None,
-    };
-
-    let tunnel_url = open_tunnel(config).await.expect("Couldn't open tunnel");
-
-    let join_url_res = utils::create_join_url(tunnel_url, password).await;
-    tokio::spawn(async move {
-        let (tx, rx) = mpsc::unbounded_channel::<(RecvData, String)>();
-        let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel::<bool>();
-
-        let shutdown_handler = window.listen("shutdown", move |_| {
-            shutdown_tx
-                .send(true)
-                .expect("Couldn't send shutdown channel msg");
-        });
-
-        let window_clone = window.clone();
-        let host_handle = window.listen("host-message", move |e| {
-            if let Some(payload) = e.payload() {
-                if let Ok(message) = serde_json::from_str::<UserMessage>(&payload) {
-                    let window_clone = window_clone.clone();
-                    tokio::spawn(async move {
-                        if let Err(err) = handle_user_message(&message, None, &window_clone).await {
-                            println!("Couldn't send host message: {:?}", err);
-                        }
-                    });
-                } else {
-                    println!("Host message conversion error");
-                }
-            }
+    let shutdown_room = room.clone();
+    let shutdown_handler = window.listen(format!("shutdown:{}", room.id), move |_| {
+        let shutdown_room = shutdown_room.clone();
+        tokio::spawn(async move {
+            chat_shutdown(&shutdown_room).await;
         });
+    });
 
-        let conn_handle = tokio::spawn(async move {
-            while let Ok((stream, _)) = listener.accept().await {
-                let tx = tx.clone();
+    let host_room = room.clone();
+    let host_handle = window.listen(format!("host-message:{}", room.id), move |e| {
+        if let Some(payload) = e.payload() {
+            if let Ok(message) = serde_json::from_str::<UserMessage>(payload) {
+                let host_room = host_room.clone();
                 tokio::spawn(async move {
-                    if let Ok(Some((mut read, uid))) = handle_connection(stream).await {
-                        while let Some(Ok(content)) = read.next().await {
-                            if let Ok(message) =
-                                serde_json::from_str::<RecvData>(&content.to_string())
-                            {
-                                tx.send((message, uid.clone()))
-                                    .expect("Couldn't send message over channel");
-                            }
-                        }
-                    } else {
-                        println!("Client connection error");
+                    if let Err(err) = handle_user_message(&host_room, &message, None).await {
+                        println!("Couldn't send host message: {:?}", err);
                     }
                 });
-            }
-        });
-
-        tokio::select! {
-            _ = handle_channel_message(rx, window.clone()) => {}
-            _ = shutdown_rx.recv() => {
-                chat_shutdown().await;
-                let _ = notify_shutdown.send(());
-                conn_handle.abort();
-                window.unlisten(shutdown_handler);
-                window.unlisten(host_handle);
-                shutdown_rx.close();
+            } else {
+                println!("Host message conversion error");
             }
         }
     });
 
-    join_url_res
+    tokio::spawn(async move {
+        let _ = closed_rx.recv().await;
+        window.unlisten(shutdown_handler);
+        window.unlisten(host_handle);
+    });
+
+    Ok(room_handle)
+}
+
+#[command]
+pub async fn approve_join(room_id: String, username: String) -> Result<(), String> {
+    approve(&room_id, &username).await
+}
+
+#[command]
+pub async fn deny_join(room_id: String, username: String) -> Result<(), String> {
+    deny(&room_id, &username).await
+}
+
+#[command]
+pub async fn get_queue_stats(room_id: String) -> Result<Vec<QueueStats>, String> {
+    queues(&room_id).await
+}
+
+#[command]
+pub async fn kick_member(
+    room_id: String,
+    username: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    kick(&room_id, &username, reason.unwrap_or_default(), false).await
+}
+
+#[command]
+pub async fn ban_member(
+    room_id: String,
+    username: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    kick(&room_id, &username, reason.unwrap_or_default(), true).await
 }
@@ -1,3 +1 @@
 pub mod chat;
-pub mod proto;
-pub mod socket;
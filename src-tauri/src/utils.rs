@@ -1,108 +1,134 @@
-use std::collections::HashMap;
-
-use aes_siv::{
-    aead::{Aead, OsRng},
-    Aes256SivAead, Key, KeyInit, Nonce,
+use serde::Serialize;
+use tauri::Window;
+use tempchat_core::{
+    client::client::exit_all,
+    events::{ChatEvent, EventSink},
+    files,
+    protocol::ServerData,
+    server::socket::handle::shutdown_all,
 };
-use rand::RngCore;
-use tauri::GlobalWindowEvent;
-
-use crate::{client::client::client_exit, server::socket::handle::chat_shutdown, structs::EncData};
 
-async fn generate_nonce() -> Nonce {
-    let mut nonce: [u8; 16] = [0; 16];
-    OsRng.fill_bytes(&mut nonce);
-    Nonce::from_slice(&nonce).to_owned()
+/// Wraps everything emitted to the window so the frontend can tell which room it belongs to
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomEvent<T> {
+    pub room_id: String,
+    pub data: T,
 }
 
-async fn pad_password(password: String) -> Vec<u8> {
-    let mut pass_vec = password.as_bytes().to_vec();
-    pass_vec.resize(64, 0);
-    pass_vec
+/// Where a received file ended up, `path` is missing if it couldn't be saved
+#[derive(Serialize)]
+struct SavedFile {
+    id: String,
+    name: String,
+    path: Option<String>,
 }
 
-pub async fn create_join_url(tunnel_url: String, password: String) -> Result<String, String> {
-    let pass_vec = pad_password(password).await;
-    let key: &Key<Aes256SivAead> = pass_vec.as_slice().into();
-    let cipher = Aes256SivAead::new(key);
-    let nonce = generate_nonce().await;
-    let encrypted_url_res = cipher.encrypt(&nonce, tunnel_url.as_bytes());
-    match encrypted_url_res {
-        Ok(encrypted_url) => {
-            let hex_url = hex::encode(encrypted_url);
-            let hex_nonce = hex::encode(nonce);
-            Ok(format!("temp://{}_{}", hex_nonce, hex_url))
+/// Forwards room events to the webview under the event names the frontend listens for
+pub struct WindowEvents(pub Window);
+
+impl WindowEvents {
+    fn emit_room<S: Serialize + Clone>(&self, event: &str, room_id: &str, data: S) {
+        let room_event = RoomEvent {
+            room_id: room_id.to_string(),
+            data,
+        };
+        if let Err(err) = self.0.emit(event, room_event) {
+            println!("Couldn't emit {} event: {:?}", event, err);
         }
-        Err(aes_siv::Error) => Err("Couldn't encrypt join url".into()),
     }
 }
 
-pub async fn parse_join_url(join_url: String, password: String) -> Result<String, String> {
-    let join_url = join_url.replace("temp://", "");
-    let split_url: Vec<&str> = join_url.splitn(2, "_").collect();
-    if split_url.len() != 2 {
-        return Err("URL is in incorrect format".into());
-    }
-    let (hex_nonce, hex_url) = (split_url[0], split_url[1]);
-    let try_nonce = hex::decode(hex_nonce);
-    let try_url = hex::decode(hex_url);
-    if try_nonce.is_err() || try_url.is_err() {
-        return Err("Could not decode URL".into());
-    }
-    let nonce = try_nonce.unwrap();
-    let nonce = Nonce::from_slice(&nonce);
-    let pass_vec = pad_password(password).await;
-    let key: &Key<Aes256SivAead> = pass_vec.as_slice().into();
-    let cipher = Aes256SivAead::new(key);
-    let decrypt_res = cipher.decrypt(&nonce, try_url.unwrap().as_slice());
-    match decrypt_res {
-        Ok(url) => {
-            let parsed_res = String::from_utf8(url);
-            if parsed_res.is_err() {
-                return Err("Couldn't decrypt URL".into());
+impl EventSink for WindowEvents {
+    fn send(&self, room_id: &str, event: ChatEvent) {
+        match event {
+            ChatEvent::Message(message) => self.emit_room(
+                "new-message",
+                room_id,
+                serde_json::to_string(&message).unwrap(),
+            ),
+            ChatEvent::MessageUpdate(update) => self.emit_room(
+                "message-update",
+                room_id,
+                serde_json::to_string(&update).unwrap(),
+            ),
+            ChatEvent::Roster(members) => {
+                self.emit_room("roster", room_id, serde_json::to_string(&members).unwrap())
+            }
+            ChatEvent::Presence(presence) => self.emit_room(
+                "presence",
+                room_id,
+                serde_json::to_string(&presence).unwrap(),
+            ),
+            ChatEvent::Join(join) => self.emit_room(
+                "join",
+                room_id,
+                serde_json::to_string(&ServerData::JoinMessage(join)).unwrap(),
+            ),
+            ChatEvent::Exit(exit) => self.emit_room(
+                "client_exit",
+                room_id,
+                serde_json::to_string(&exit).unwrap(),
+            ),
+            ChatEvent::ExpireMessage(message_id) => {
+                self.emit_room("expire-message", room_id, message_id)
+            }
+            ChatEvent::ShutdownWarning(warning) => self.emit_room(
+                "shutdown-warning",
+                room_id,
+                serde_json::to_string(&warning).unwrap(),
+            ),
+            ChatEvent::Direct(direct) => self.emit_room(
+                "direct-message",
+                room_id,
+                serde_json::to_string(&direct).unwrap(),
+            ),
+            ChatEvent::FileOffer(info) => {
+                self.emit_room("file-offer", room_id, serde_json::to_string(&info).unwrap())
+            }
+            ChatEvent::FileProgress(progress) => self.emit_room(
+                "file-progress",
+                room_id,
+                serde_json::to_string(&progress).unwrap(),
+            ),
+            ChatEvent::FileReceived(file) => {
+                let saved = tauri::api::path::download_dir()
+                    .ok_or_else(|| "No downloads folder".to_string())
+                    .and_then(|dir| files::save_file(&dir, &file.info.name, &file.data));
+                if let Err(err) = &saved {
+                    println!("Couldn't save received file: {}", err);
+                }
+                let saved_file = SavedFile {
+                    id: file.info.id,
+                    name: file.info.name,
+                    path: saved.ok().map(|path| path.display().to_string()),
+                };
+                self.emit_room(
+                    "file-received",
+                    room_id,
+                    serde_json::to_string(&saved_file).unwrap(),
+                )
+            }
+            ChatEvent::JoinRequest(request) => self.emit_room(
+                "join-request",
+                room_id,
+                serde_json::to_string(&request).unwrap(),
+            ),
+            ChatEvent::JoinPending => self.emit_room("join-pending", room_id, ()),
+            ChatEvent::Kicked(kick) => {
+                self.emit_room("kicked", room_id, serde_json::to_string(&kick).unwrap())
             }
-            Ok(parsed_res.unwrap())
+            ChatEvent::Disconnected => self.emit_room("disconnected", room_id, ()),
+            ChatEvent::Reconnected => self.emit_room("reconnected", room_id, ()),
+            ChatEvent::Error(err) => self.emit_room("error", room_id, err),
+            ChatEvent::Shutdown => self.emit_room("shutdown", room_id, ()),
         }
-        Err(aes_siv::Error) => Err("Couldn't decrypt URL".into()),
     }
 }
 
-pub async fn decrypt_message(
-    enc_data: &EncData,
-    cipher: &Aes256SivAead,
-) -> Result<Vec<u8>, String> {
-    let nonce = enc_data.nonce.as_slice();
-    let decrypt_res = cipher.decrypt(nonce.into(), enc_data.data.as_slice());
-    match decrypt_res {
-        Ok(decrypted) => Ok(decrypted),
-        Err(_err) => Err("Couldn't decrypt message data".into()),
-    }
-}
-
-pub async fn encrypt_message(message: String, cipher: &Aes256SivAead) -> Result<EncData, String> {
-    let nonce = generate_nonce().await;
-    let cipher_message_res = cipher.encrypt(&nonce, message.as_bytes());
-    match cipher_message_res {
-        Ok(cipher_message) => Ok(EncData {
-            nonce: nonce.to_vec(),
-            data: cipher_message,
-        }),
-        Err(_err) => Err("Couldn't ecnrypt message".into()),
-    }
-}
-
-pub fn handle_exit(event: GlobalWindowEvent) {
-    let url = event.window().url();
-    match url.path() {
-        "/chat" => {
-            let queries: HashMap<String, String> = url.query_pairs().into_owned().collect();
-            let is_host = queries.get("type").is_some_and(|val| val == "host");
-            if is_host {
-                tauri::async_runtime::block_on(chat_shutdown());
-            } else {
-                tauri::async_runtime::block_on(client_exit());
-            };
-        }
-        _ => {}
-    }
+pub fn handle_exit() {
+    tauri::async_runtime::block_on(async {
+        shutdown_all().await;
+        exit_all().await;
+    });
 }
@@ -0,0 +1,448 @@
+//! Terminal frontend for TempChat. Hosts or joins a room using the same engine as the desktop app,
+//! printing the conversation to stdout and sending each line read from stdin.
+//!
+//!     tempchat-cli host <username> [--limit <n>] [--strength low|medium|high] [--history] [--ttl <secs>] [--lifetime <secs>] [--idle <secs>] [--approve] [--max-file <bytes>] [--heartbeat <secs>] [--slow drop|disconnect] [--lan | --lan-address <ip> | --relay <url> | --tunnel <url>]
+//!     tempchat-cli join <username> <join url> [--heartbeat <secs>]
+//!
+//! The room password is read from `TEMPCHAT_PASSWORD`, or prompted for when it isn't set.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tempchat_core::client::client::{self, client_exit, connect_chat, send_message};
+use tempchat_core::events::{ChatEvent, EventSink};
+use tempchat_core::files::{self, DEFAULT_MAX_FILE_SIZE};
+use tempchat_core::server::chat::{host_chat, HostOptions};
+use tempchat_core::server::outbox::{QueueStats, SlowConsumer};
+use tempchat_core::server::proto::{Room, Transport};
+use tempchat_core::server::socket::handle::{
+    accept_file, approve_join, chat_shutdown, deny_join, handle_user_message, kick_member,
+    presence_list, queue_stats, send_direct, share_file,
+};
+use tempchat_core::structs::{Heartbeat, MessageAction, Presence, PresenceState, UserMessage};
+use tempchat_core::utils::{self, KdfStrength};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::broadcast;
+
+const USAGE: &str = "Usage:
+  tempchat-cli host <username> [--limit <n>] [--strength low|medium|high] [--history] [--ttl <secs>] [--lifetime <secs>] [--idle <secs>] [--approve] [--max-file <bytes>] [--heartbeat <secs>] [--slow drop|disconnect] [--lan | --lan-address <ip> | --relay <url> | --tunnel <url>]
+  tempchat-cli join <username> <join url> [--heartbeat <secs>]
+
+/who lists everyone in the room and whether they're around.
+/msg <username> <message> sends a direct message only they can read.
+/send <path> offers a file to the room and /get <id> downloads one into the current directory.
+While hosting, /kick <username> [reason] removes a member and /ban <username> [reason] keeps them out.
+With --approve, joiners wait until /approve <username> or /deny <username>.
+/queues shows how much is waiting to go out to each member. --slow picks whether members who
+fall behind lose their oldest frames or get disconnected until they resume.";
+
+/// Prints room events to the terminal
+struct Terminal;
+
+impl EventSink for Terminal {
+    fn send(&self, _room_id: &str, event: ChatEvent) {
+        match event {
+            ChatEvent::Message(message) => {
+                let marker = if message.verified {
+                    ""
+                } else {
+                    " (unverified)"
+                };
+                println!(
+                    "[{}] {}{}: {}",
+                    message.created, message.sender, marker, message.content
+                );
+            }
+            ChatEvent::Direct(direct) => {
+                let marker = if direct.message.verified {
+                    ""
+                } else {
+                    " (unverified)"
+                };
+                let arrow = if direct.message.sender == direct.peer {
+                    "from"
+                } else {
+                    "to"
+                };
+                println!(
+                    "[{}] (direct {} {}){}: {}",
+                    direct.message.created, arrow, direct.peer, marker, direct.message.content
+                );
+            }
+            ChatEvent::MessageUpdate(update) => match update.action {
+                MessageAction::Edit { content, .. } => {
+                    let marker = if update.verified { "" } else { " (unverified)" };
+                    println!(
+                        "* {} edited a message{}: {}",
+                        update.username, marker, content
+                    )
+                }
+                MessageAction::Delete { .. } => println!("* {} deleted a message", update.username),
+                MessageAction::React { emoji, .. } => {
+                    println!("* {} reacted {}", update.username, emoji)
+                }
+                // Only worth showing while the reaction is there
+                MessageAction::Unreact { .. } => {}
+            },
+            ChatEvent::Roster(members) => {
+                let names: Vec<String> = members
+                    .iter()
+                    .map(|member| {
+                        if member.host {
+                            format!("{} (host)", member.username)
+                        } else {
+                            member.username.clone()
+                        }
+                    })
+                    .collect();
+                println!("* In the chat: {}", names.join(", "))
+            }
+            ChatEvent::Presence(presence) => match presence.state {
+                PresenceState::Idle => println!("* {} is idle", presence.username),
+                PresenceState::Online => println!("* {} is online", presence.username),
+                // Too chatty for a terminal, /who shows it
+                PresenceState::Typing | PresenceState::Offline => {}
+            },
+            ChatEvent::Join(join) => println!("* {} joined", join.joined),
+            ChatEvent::Exit(exit) => println!("* {} left", exit.username),
+            ChatEvent::ShutdownWarning(warning) => {
+                println!("* The chat closes in {} seconds", warning.seconds_left)
+            }
+            ChatEvent::FileOffer(info) => println!(
+                "* {} shared {} ({} bytes), /get {} to download it",
+                info.sender, info.name, info.size, info.id
+            ),
+            ChatEvent::FileProgress(progress) => {
+                if progress.outgoing && progress.bytes == progress.size {
+                    println!("* Sent file {} to {}", progress.id, progress.peer);
+                }
+            }
+            ChatEvent::FileReceived(file) => {
+                match files::save_file(Path::new("."), &file.info.name, &file.data) {
+                    Ok(path) => println!(
+                        "* Saved {} from {} to {}",
+                        file.info.name,
+                        file.info.sender,
+                        path.display()
+                    ),
+                    Err(err) => println!("! {}", err),
+                }
+            }
+            ChatEvent::JoinRequest(request) => println!(
+                "* {} wants to join (safety number {}), /approve or /deny them",
+                request.username, request.fingerprint
+            ),
+            ChatEvent::JoinPending => println!("* Waiting for the host to let you in"),
+            ChatEvent::Kicked(kick) => {
+                let action = if kick.banned { "banned" } else { "removed" };
+                if kick.reason.is_empty() {
+                    println!("* The host {} you from the chat", action)
+                } else {
+                    println!("* The host {} you from the chat: {}", action, kick.reason)
+                }
+            }
+            ChatEvent::Disconnected => println!("* Lost the connection, reconnecting..."),
+            ChatEvent::Reconnected => println!("* Reconnected"),
+            ChatEvent::Error(err) => println!("! {}", err),
+            // Already printed, nothing to take back from the terminal
+            ChatEvent::ExpireMessage(_) => {}
+            ChatEvent::Shutdown => println!("* The host closed the chat"),
+        }
+    }
+}
+
+fn read_password() -> Result<String, String> {
+    if let Ok(password) = std::env::var("TEMPCHAT_PASSWORD") {
+        return Ok(password);
+    }
+    print!("Password: ");
+    let _ = std::io::stdout().flush();
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(|_| "Couldn't read password")?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn identity_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".tempchat"))
+}
+
+fn parse_heartbeat(value: &str) -> Result<Heartbeat, String> {
+    match value.parse() {
+        Ok(interval) if interval > 0 => Ok(Heartbeat::every(interval)),
+        _ => Err("Invalid heartbeat interval".into()),
+    }
+}
+
+fn parse_host_options(username: String, args: &[String]) -> Result<HostOptions, String> {
+    let mut options = HostOptions {
+        username,
+        user_limit: 2,
+        password: String::new(),
+        kdf_strength: KdfStrength::default(),
+        transport: Transport::default(),
+        share_history: false,
+        message_ttl: None,
+        max_lifetime: None,
+        idle_timeout: None,
+        require_approval: false,
+        max_file_size: DEFAULT_MAX_FILE_SIZE,
+        heartbeat: Heartbeat::default(),
+        slow_consumer: SlowConsumer::default(),
+        tunnel_provider: None,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--limit" => {
+                options.user_limit = value()?.parse().map_err(|_| "Invalid user limit")?;
+            }
+            "--strength" => {
+                options.kdf_strength = match value()?.as_str() {
+                    "low" => KdfStrength::Low,
+                    "medium" => KdfStrength::Medium,
+                    "high" => KdfStrength::High,
+                    other => return Err(format!("Unknown link strength {}", other)),
+                };
+            }
+            "--heartbeat" => options.heartbeat = parse_heartbeat(value()?)?,
+            "--slow" => {
+                options.slow_consumer = match value()?.as_str() {
+                    "drop" => SlowConsumer::DropOldest,
+                    "disconnect" => SlowConsumer::Disconnect,
+                    other => return Err(format!("Unknown slow consumer policy {}", other)),
+                };
+            }
+            "--lan" => options.transport = Transport::Lan { address: None },
+            "--lan-address" => {
+                options.transport = Transport::Lan {
+                    address: Some(value()?.clone()),
+                };
+            }
+            "--history" => options.share_history = true,
+            "--approve" => options.require_approval = true,
+            "--max-file" => {
+                options.max_file_size = value()?.parse().map_err(|_| "Invalid file size")?;
+            }
+            "--lifetime" => {
+                options.max_lifetime = Some(value()?.parse().map_err(|_| "Invalid lifetime")?);
+            }
+            "--idle" => {
+                options.idle_timeout = Some(value()?.parse().map_err(|_| "Invalid idle timeout")?);
+            }
+            "--ttl" => {
+                options.message_ttl = Some(value()?.parse().map_err(|_| "Invalid message TTL")?);
+            }
+            "--relay" => {
+                options.transport = Transport::Relay {
+                    server: value()?.clone(),
+                }
+            }
+            "--tunnel" => {
+                options.transport = Transport::Tunnel {
+                    server: Some(value()?.clone()),
+                }
+            }
+            other => return Err(format!("Unknown option {}", other)),
+        }
+    }
+    Ok(options)
+}
+
+/// Feeds stdin lines to `send` until stdin closes, the room shuts down or we're interrupted
+async fn chat_loop<F, Fut>(mut shutdown_rx: broadcast::Receiver<()>, send: F)
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Result<(), String>>,
+{
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) => {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    if let Err(err) = send(line).await {
+                        println!("! {}", err);
+                    }
+                }
+                _ => return,
+            },
+            _ = shutdown_rx.recv() => return,
+            _ = tokio::signal::ctrl_c() => return,
+        }
+    }
+}
+
+/// Runs a host command such as `/kick alice spamming`, `/ban bob` or `/approve carol`
+async fn moderate(room: &Room, command: &str) -> Result<(), String> {
+    let mut parts = command.splitn(3, ' ');
+    let name = parts.next().unwrap_or_default();
+    let username = parts
+        .next()
+        .filter(|username| !username.is_empty())
+        .ok_or_else(|| "Missing username".to_string())?;
+    let reason = parts.next().unwrap_or_default().to_string();
+    match name {
+        "send" => {
+            let (name, data) = read_shared_file(command.trim_start_matches("send ").trim())?;
+            share_file(room, name, data).await
+        }
+        "get" => accept_file(room, username).await,
+        "kick" => kick_member(room, username, reason, false).await,
+        "ban" => kick_member(room, username, reason, true).await,
+        "approve" => approve_join(room, username).await,
+        "deny" => deny_join(room, username).await,
+        "msg" => send_direct(room, username, reason).await,
+        _ => Err(
+            "Commands are /who, /queues, /msg, /send, /get, /kick, /ban, /approve and /deny".into(),
+        ),
+    }
+}
+
+fn print_members(members: Vec<Presence>) {
+    for member in members {
+        let state = match member.state {
+            PresenceState::Online => "online",
+            PresenceState::Idle => "idle",
+            PresenceState::Typing => "typing",
+            PresenceState::Offline => "offline",
+        };
+        println!("* {} ({})", member.username, state);
+    }
+}
+
+fn print_queues(queues: Vec<QueueStats>) {
+    for queue in queues {
+        let connection = if queue.connected {
+            ""
+        } else {
+            ", disconnected"
+        };
+        println!(
+            "* {}: {} frames ({} bytes) waiting, peak {} bytes, {} dropped{}",
+            queue.username, queue.frames, queue.bytes, queue.peak_bytes, queue.dropped, connection
+        );
+    }
+}
+
+fn read_shared_file(path: &str) -> Result<(String, Vec<u8>), String> {
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| "Usage: /send <path>".to_string())?
+        .to_string();
+    let data = std::fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path, err))?;
+    Ok((name, data))
+}
+
+async fn host(username: String, args: &[String]) -> Result<(), String> {
+    let mut options = parse_host_options(username, args)?;
+    options.password = read_password()?;
+    let identity = utils::load_identity(identity_dir());
+    let safety_number = utils::safety_number(identity.verifying_key().as_bytes());
+
+    let (room_handle, room) = host_chat(options, identity, Arc::new(Terminal)).await?;
+    println!("Join URL: {}", room_handle.join_url);
+    println!("Safety number: {}", safety_number);
+
+    chat_loop(room.shutdown.subscribe(), |content| {
+        let room = room.clone();
+        async move {
+            if content == "/who" {
+                print_members(presence_list(&room).await);
+                return Ok(());
+            }
+            if content == "/queues" {
+                print_queues(queue_stats(&room).await);
+                return Ok(());
+            }
+            if let Some(command) = content.strip_prefix('/') {
+                return moderate(&room, command).await;
+            }
+            let message = UserMessage {
+                content,
+                signature: Vec::new(),
+                ttl: None,
+            };
+            handle_user_message(&room, &message, None).await
+        }
+    })
+    .await;
+    chat_shutdown(&room).await;
+    Ok(())
+}
+
+async fn join(username: String, chat_url: String, args: &[String]) -> Result<(), String> {
+    let heartbeat = match args {
+        [] => Heartbeat::default(),
+        [flag, interval] if flag == "--heartbeat" => parse_heartbeat(interval)?,
+        _ => return Err(USAGE.to_string()),
+    };
+    let password = read_password()?;
+    let member_secret = utils::load_member_secret(identity_dir());
+    let room = connect_chat(
+        username,
+        chat_url,
+        password,
+        member_secret,
+        heartbeat,
+        Arc::new(Terminal),
+    )
+    .await?;
+
+    chat_loop(room.shutdown.subscribe(), |content| {
+        let room = room.clone();
+        async move {
+            if content == "/who" {
+                print_members(client::presence(&room.id).await?);
+                return Ok(());
+            }
+            if let Some(path) = content.strip_prefix("/send ") {
+                let (name, data) = read_shared_file(path.trim())?;
+                return client::share_file(&room, name, data).await;
+            }
+            if let Some(id) = content.strip_prefix("/get ") {
+                return client::accept_file(&room, id.trim()).await;
+            }
+            if let Some(direct) = content.strip_prefix("/msg ") {
+                let (recipient, text) = direct
+                    .split_once(' ')
+                    .ok_or_else(|| "Usage: /msg <username> <message>".to_string())?;
+                return client::send_direct(&room, recipient, text.to_string()).await;
+            }
+            let message = UserMessage {
+                content,
+                signature: Vec::new(),
+                ttl: None,
+            };
+            send_message(&room, message).await
+        }
+    })
+    .await;
+    client_exit(&room).await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let res = match args.as_slice() {
+        [mode, username, rest @ ..] if mode == "host" => host(username.clone(), rest).await,
+        [mode, username, url, rest @ ..] if mode == "join" => {
+            join(username.clone(), url.clone(), rest).await
+        }
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,226 @@
+//! Blind relay for TempChat rooms. Hosts register a room over `/host/<room>`, members connect to
+//! `/join/<room>` and the host opens an `/accept/<room>/<conn>` socket for each of them. Frames are
+//! passed through untouched once they parse as protocol messages, the relay never holds any keys.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::lock::Mutex;
+use futures_util::{SinkExt, StreamExt};
+use tempchat_core::protocol::{ClientData, ServerData};
+use tempchat_core::structs::RelayControl;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use uuid::Uuid;
+
+const DEFAULT_BIND: &str = "0.0.0.0:8080";
+// How long a member waits for the host to pick up their connection
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(15);
+
+enum Route {
+    Host(String),
+    Join(String),
+    Accept(String, String),
+}
+
+fn parse_route(path: &str) -> Option<Route> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match parts.as_slice() {
+        ["host", room] if !room.is_empty() => Some(Route::Host(room.to_string())),
+        ["join", room] if !room.is_empty() => Some(Route::Join(room.to_string())),
+        ["accept", room, conn] if !room.is_empty() && !conn.is_empty() => {
+            Some(Route::Accept(room.to_string(), conn.to_string()))
+        }
+        _ => None,
+    }
+}
+
+struct PendingMember {
+    room_id: String,
+    socket: WebSocketStream<TcpStream>,
+}
+
+#[derive(Default)]
+struct Relay {
+    rooms: HashMap<String, mpsc::UnboundedSender<Message>>,
+    pending: HashMap<String, PendingMember>,
+}
+
+type SharedRelay = Arc<Mutex<Relay>>;
+
+async fn handle_stream(relay: SharedRelay, stream: TcpStream) {
+    let mut route = None;
+    // The error type is tungstenite's, the handshake callback has to return it as is
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        route = parse_route(request.uri().path());
+        if route.is_some() {
+            Ok(response)
+        } else {
+            let mut error = ErrorResponse::new(Some("Unknown route".into()));
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            Err(error)
+        }
+    };
+    let socket = match tokio_tungstenite::accept_hdr_async(stream, callback).await {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+
+    match route {
+        Some(Route::Host(room_id)) => handle_host(relay, room_id, socket).await,
+        Some(Route::Join(room_id)) => handle_join(relay, room_id, socket).await,
+        Some(Route::Accept(room_id, conn_id)) => {
+            handle_accept(relay, room_id, conn_id, socket).await
+        }
+        None => {}
+    }
+}
+
+async fn handle_host(relay: SharedRelay, room_id: String, socket: WebSocketStream<TcpStream>) {
+    let (mut write, mut read) = socket.split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+
+    {
+        let mut relay = relay.lock().await;
+        if relay.rooms.contains_key(&room_id) {
+            let _ = write.close().await;
+            return;
+        }
+        relay.rooms.insert(room_id.clone(), tx);
+    }
+    println!("Room {} registered", room_id);
+
+    let forward = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if write.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    // Nothing is expected from the host on this socket, it only signals the room is alive
+    while let Some(Ok(message)) = read.next().await {
+        if message.is_close() {
+            break;
+        }
+    }
+    forward.abort();
+
+    let mut relay = relay.lock().await;
+    relay.rooms.remove(&room_id);
+    relay.pending.retain(|_, member| member.room_id != room_id);
+    println!("Room {} closed", room_id);
+}
+
+async fn handle_join(relay: SharedRelay, room_id: String, mut socket: WebSocketStream<TcpStream>) {
+    let conn_id = Uuid::new_v4().to_string();
+    let mut relay_data = relay.lock().await;
+    let try_control = relay_data.rooms.get(&room_id).cloned();
+    if try_control.is_none() {
+        drop(relay_data);
+        let _ = socket.close(None).await;
+        return;
+    }
+
+    let connect = serde_json::to_string(&RelayControl::Connect {
+        conn_id: conn_id.clone(),
+    })
+    .unwrap();
+    if try_control.unwrap().send(Message::Text(connect)).is_err() {
+        drop(relay_data);
+        let _ = socket.close(None).await;
+        return;
+    }
+    relay_data
+        .pending
+        .insert(conn_id.clone(), PendingMember { room_id, socket });
+    drop(relay_data);
+
+    tokio::time::sleep(ACCEPT_TIMEOUT).await;
+    if let Some(mut member) = relay.lock().await.pending.remove(&conn_id) {
+        let _ = member.socket.close(None).await;
+    }
+}
+
+async fn handle_accept(
+    relay: SharedRelay,
+    room_id: String,
+    conn_id: String,
+    mut socket: WebSocketStream<TcpStream>,
+) {
+    let try_member = {
+        let mut relay = relay.lock().await;
+        match relay.pending.get(&conn_id) {
+            Some(member) if member.room_id == room_id => relay.pending.remove(&conn_id),
+            _ => None,
+        }
+    };
+    let member = match try_member {
+        Some(member) => member,
+        None => {
+            let _ = socket.close(None).await;
+            return;
+        }
+    };
+
+    let (mut member_write, mut member_read) = member.socket.split();
+    let (mut host_write, mut host_read) = socket.split();
+
+    let to_host = async {
+        while let Some(Ok(message)) = member_read.next().await {
+            if !valid_frame::<ClientData>(&message) {
+                continue;
+            }
+            if host_write.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = host_write.close().await;
+    };
+    let to_member = async {
+        while let Some(Ok(message)) = host_read.next().await {
+            if !valid_frame::<ServerData>(&message) {
+                continue;
+            }
+            if member_write.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = member_write.close().await;
+    };
+    tokio::select! {
+        _ = to_host => {}
+        _ = to_member => {}
+    }
+}
+
+/// Only protocol frames are passed on. Anything that doesn't parse is dropped so the relay can't
+/// be used as a general purpose pipe, the encrypted payloads themselves are never inspected.
+fn valid_frame<T: serde::de::DeserializeOwned>(message: &Message) -> bool {
+    match message {
+        Message::Text(text) => serde_json::from_str::<T>(text).is_ok(),
+        Message::Close(_) | Message::Ping(_) | Message::Pong(_) => true,
+        _ => false,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let bind = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_BIND.to_string());
+    let listener = TcpListener::bind(&bind)
+        .await
+        .unwrap_or_else(|_| panic!("Unable to bind relay to {}", bind));
+    println!("Relay listening on {}", bind);
+
+    let relay: SharedRelay = Arc::new(Mutex::new(Relay::default()));
+    while let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(handle_stream(relay.clone(), stream));
+    }
+}
@@ -0,0 +1,1044 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    client::proto::{Client, ClientRoom},
+    events::{ChatEvent, EventSink},
+    files::{self, Transfers},
+    protocol::{self, ClientData, ServerData},
+    server::proto::Exit,
+    structs::{
+        BroadcastMessage, DirectChat, DirectMessage, FileAccept, FileChunk, FileComplete,
+        FileOffer, FileProgress, Handshake, Heartbeat, Join, Member, MessageAction, MessageUpdate,
+        Presence, PresenceState, Resume, UserMessage,
+    },
+    utils,
+};
+use aes_siv::{aead::OsRng, Aes256SivAead, Key, KeyInit};
+use chrono::Local;
+use futures_util::{lock::Mutex, stream::SplitStream, SinkExt, StreamExt};
+use once_cell::sync::Lazy;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::Message::{self, Text},
+    MaybeTlsStream, WebSocketStream,
+};
+use uuid::Uuid;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+static CLIENTS: Lazy<Mutex<HashMap<String, Arc<ClientRoom>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Checks the signature and that the key matches the one we've seen for this username,
+/// pinning it on first sight for members who were here before us.
+fn verify_broadcast(client: &mut Client, message: &BroadcastMessage) -> bool {
+    let signed = if message.edited {
+        utils::verify_edit(
+            &message.id,
+            &message.content,
+            &message.signature,
+            &message.signing_key,
+        )
+    } else {
+        utils::verify_message(&message.content, &message.signature, &message.signing_key)
+    };
+    if !signed {
+        return false;
+    }
+    pin_key(client, &message.sender, &message.signing_key)
+}
+
+/// Like `verify_broadcast`, but the signature has to cover our key and the sender has to be the
+/// one the host says routed it
+fn verify_direct(client: &mut Client, sender: &str, message: &BroadcastMessage) -> bool {
+    let own_key = match client.signing_key.as_ref() {
+        Some(signing_key) => signing_key.verifying_key().to_bytes(),
+        None => return false,
+    };
+    if message.sender != sender
+        || !utils::verify_direct(
+            &message.content,
+            &own_key,
+            &message.signature,
+            &message.signing_key,
+        )
+    {
+        return false;
+    }
+    pin_key(client, &message.sender, &message.signing_key)
+}
+
+fn pin_key(client: &mut Client, username: &str, signing_key: &[u8]) -> bool {
+    match client.known_keys.get(username) {
+        Some(known_key) => known_key == signing_key,
+        None => {
+            client
+                .known_keys
+                .insert(username.to_string(), signing_key.to_vec());
+            true
+        }
+    }
+}
+
+/// Reports the message as expired once its TTL runs out so the frontend can drop it
+fn expire_after(events: &Arc<dyn EventSink>, room_id: &str, message: &BroadcastMessage) {
+    if let Some(ttl) = message.ttl {
+        let (events, room_id, message_id) =
+            (events.clone(), room_id.to_string(), message.id.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(ttl)).await;
+            events.send(&room_id, ChatEvent::ExpireMessage(message_id));
+        });
+    }
+}
+
+async fn handle_recv_data(
+    room: Arc<ClientRoom>,
+    mut rx: mpsc::UnboundedReceiver<ServerData>,
+    events: Arc<dyn EventSink>,
+) {
+    loop {
+        let res = rx.recv().await;
+        if res.is_none() {
+            // Connection to the host is gone
+            return;
+        }
+        if let Some(recv_data) = res {
+            if !matches!(recv_data, ServerData::Resumed(_) | ServerData::ResumeFailed) {
+                room.client.lock().await.received += 1;
+            }
+            match recv_data {
+                ServerData::EncData(enc_data) => {
+                    let mut client = room.client.lock().await;
+                    if enc_data.epoch != client.epoch {
+                        // Encrypted under a key we've rotated away from (or haven't received yet)
+                        continue;
+                    }
+                    if client.chat_key.is_some() {
+                        let key: &Key<Aes256SivAead> =
+                            client.chat_key.as_mut().unwrap().as_slice().into();
+                        let decrypted_res =
+                            utils::decrypt_message(&enc_data, &Aes256SivAead::new(key)).await;
+                        match decrypted_res {
+                            Ok(dec_data) => {
+                                if let Ok(mut broadcast_data) =
+                                    serde_json::from_slice::<BroadcastMessage>(&dec_data)
+                                {
+                                    broadcast_data.verified =
+                                        verify_broadcast(&mut client, &broadcast_data);
+                                    expire_after(&events, &room.id, &broadcast_data);
+                                    events.send(&room.id, ChatEvent::Message(broadcast_data));
+                                }
+                            }
+                            Err(err) => {
+                                send_err(events.as_ref(), &room.id, err).await;
+                            }
+                        }
+                    }
+                }
+                ServerData::JoinMessage(data) => {
+                    let mut client = room.client.lock().await;
+                    // A name that was already pinned keeps its first key, so someone joining
+                    // under it later can't take over its signatures
+                    client
+                        .known_keys
+                        .entry(data.joined.clone())
+                        .or_insert_with(|| data.signing_key.clone());
+                    let member = Member::new(
+                        data.joined.clone(),
+                        data.signing_key.clone(),
+                        data.direct_key.clone(),
+                        data.direct_key_signature.clone(),
+                        PresenceState::Online,
+                        false,
+                    );
+                    client.members.insert(data.joined.clone(), member);
+                    drop(client);
+                    events.send(&room.id, ChatEvent::Join(data));
+                }
+                ServerData::Handshake(handshake) => {
+                    if !protocol::supported_version(handshake.protocol_version) {
+                        let err = format!(
+                            "Incompatible protocol version: the host speaks v{}, this app speaks v{}",
+                            handshake.protocol_version,
+                            protocol::PROTOCOL_VERSION
+                        );
+                        send_err(events.as_ref(), &room.id, err).await;
+                        continue;
+                    }
+                    let mut client = room.client.lock().await;
+                    let (try_secret, try_pub) =
+                        (client.handshake_secret.take(), client.handshake_pub.take());
+                    if try_secret.is_none() || try_pub.is_none() {
+                        send_err(
+                            events.as_ref(),
+                            &room.id,
+                            "Unexpected handshake from server".into(),
+                        )
+                        .await;
+                        continue;
+                    }
+                    let client_pub = try_pub.unwrap();
+                    let host_fingerprint = client.host_fingerprint.clone();
+                    let session_res =
+                        utils::parse_handshake_key(&handshake.pub_key).and_then(|host_pub| {
+                            if utils::fingerprint(&handshake.identity_key) != host_fingerprint
+                                || !utils::verify_handshake(
+                                    &client_pub,
+                                    &host_pub,
+                                    &handshake.signature,
+                                    &handshake.identity_key,
+                                )
+                            {
+                                return Err("Couldn't verify the host's identity".into());
+                            }
+                            let shared = try_secret.unwrap().diffie_hellman(&host_pub);
+                            utils::derive_session_cipher(&shared, &client_pub, &host_pub)
+                        });
+                    match session_res {
+                        Ok(cipher) => {
+                            client.session_cipher = Some(cipher);
+                            client.max_file_size = handshake.max_file_size;
+                            client.message_ttl = handshake.message_ttl;
+                            client.host_key = Some(handshake.identity_key);
+                        }
+                        Err(err) => {
+                            send_err(events.as_ref(), &room.id, err).await;
+                        }
+                    }
+                }
+                ServerData::KeyMessage(msg) | ServerData::KeyRotation(msg) => {
+                    let mut client = room.client.lock().await;
+                    if client.session_cipher.is_none() {
+                        send_err(
+                            events.as_ref(),
+                            &room.id,
+                            "Handshake with server not completed".into(),
+                        )
+                        .await;
+                        continue;
+                    }
+                    if client.chat_key.is_some() && msg.epoch <= client.epoch {
+                        println!("Ignoring chat key from stale epoch {}", msg.epoch);
+                        continue;
+                    }
+                    let dec_data =
+                        utils::decrypt_message(&msg.key, client.session_cipher.as_ref().unwrap())
+                            .await;
+                    match dec_data {
+                        Ok(chat_key) => {
+                            client.chat_key = Some(chat_key);
+                            client.epoch = msg.epoch;
+                            if let Some(enc_token) = msg.resume_token {
+                                let cipher = client.session_cipher.as_ref().unwrap();
+                                client.resume_token =
+                                    utils::decrypt_message(&enc_token, cipher).await.ok();
+                            }
+                        }
+                        Err(err) => {
+                            send_err(events.as_ref(), &room.id, err).await;
+                        }
+                    }
+                }
+                ServerData::Resumed(resumed) => {
+                    let mut client = room.client.lock().await;
+                    let try_token = match client.session_cipher.as_ref() {
+                        Some(cipher) => utils::decrypt_message(&resumed.token, cipher).await.ok(),
+                        None => None,
+                    };
+                    client.resume_token = try_token;
+                    client.received += resumed.skipped;
+                    println!("Resumed session, catching up on missed messages");
+                }
+                ServerData::ResumeFailed => {
+                    // The host let our slot go, join like it's the first time
+                    if let Err(err) = send_join(&room).await {
+                        send_err(events.as_ref(), &room.id, err).await;
+                    }
+                }
+                ServerData::Roster(enc_data) => {
+                    let mut client = room.client.lock().await;
+                    let try_roster = match client.session_cipher.as_ref() {
+                        Some(cipher) => utils::decrypt_message(&enc_data, cipher)
+                            .await
+                            .ok()
+                            .and_then(|data| serde_json::from_slice::<Vec<Member>>(&data).ok()),
+                        None => None,
+                    };
+                    if let Some(roster) = try_roster {
+                        for member in roster.iter() {
+                            // The host vouches for these the same way it does for joins
+                            client
+                                .known_keys
+                                .entry(member.username.clone())
+                                .or_insert_with(|| member.signing_key.clone());
+                            client
+                                .members
+                                .insert(member.username.clone(), member.clone());
+                        }
+                        events.send(&room.id, ChatEvent::Roster(roster));
+                    }
+                }
+                ServerData::History(messages) => {
+                    let mut client = room.client.lock().await;
+                    if client.session_cipher.is_none() {
+                        continue;
+                    }
+                    for enc_data in messages {
+                        let dec_data = utils::decrypt_message(
+                            &enc_data,
+                            client.session_cipher.as_ref().unwrap(),
+                        )
+                        .await;
+                        let try_message = dec_data.ok().and_then(|data| {
+                            serde_json::from_slice::<BroadcastMessage>(&data).ok()
+                        });
+                        if let Some(mut message) = try_message {
+                            message.verified = verify_broadcast(&mut client, &message);
+                            expire_after(&events, &room.id, &message);
+                            events.send(&room.id, ChatEvent::Message(message));
+                        }
+                    }
+                }
+                ServerData::Kick(kick) => {
+                    events.send(&room.id, ChatEvent::Kicked(kick));
+                    let mut client = room.client.lock().await;
+                    if let Some(write) = client.write.as_mut() {
+                        let _ = write.close().await;
+                    }
+                    client.write = None;
+                    drop(client);
+                    CLIENTS.lock().await.remove(&room.id);
+                    let _ = room.shutdown.send(());
+                }
+                ServerData::ShutdownWarning(warning) => {
+                    events.send(&room.id, ChatEvent::ShutdownWarning(warning));
+                }
+                ServerData::FileOffer(enc_data) => {
+                    let mut client = room.client.lock().await;
+                    if enc_data.epoch != client.epoch || client.chat_key.is_none() {
+                        continue;
+                    }
+                    let key: &Key<Aes256SivAead> =
+                        client.chat_key.as_ref().unwrap().as_slice().into();
+                    let try_offer = utils::decrypt_message(&enc_data, &Aes256SivAead::new(key))
+                        .await
+                        .ok()
+                        .and_then(|data| serde_json::from_slice::<FileOffer>(&data).ok());
+                    if let Some(offer) = try_offer {
+                        let info = offer.info.clone();
+                        // Our own offers come back to us too, there's nothing to accept
+                        if info.sender != client.username {
+                            client.transfers.offered(offer);
+                        }
+                        events.send(&room.id, ChatEvent::FileOffer(info));
+                    }
+                }
+                ServerData::MessageUpdate(enc_data) => {
+                    let client = room.client.lock().await;
+                    if enc_data.epoch != client.epoch || client.chat_key.is_none() {
+                        continue;
+                    }
+                    let key: &Key<Aes256SivAead> =
+                        client.chat_key.as_ref().unwrap().as_slice().into();
+                    let try_update = utils::decrypt_message(&enc_data, &Aes256SivAead::new(key))
+                        .await
+                        .ok()
+                        .and_then(|data| serde_json::from_slice::<MessageUpdate>(&data).ok());
+                    if let Some(mut update) = try_update {
+                        update.verified = match &update.action {
+                            MessageAction::Edit {
+                                id,
+                                content,
+                                signature,
+                            } => client
+                                .known_keys
+                                .get(&update.username)
+                                .is_some_and(|key| utils::verify_edit(id, content, signature, key)),
+                            _ => false,
+                        };
+                        events.send(&room.id, ChatEvent::MessageUpdate(update));
+                    }
+                }
+                ServerData::Presence(enc_data) => {
+                    let mut client = room.client.lock().await;
+                    if enc_data.epoch != client.epoch || client.chat_key.is_none() {
+                        continue;
+                    }
+                    let key: &Key<Aes256SivAead> =
+                        client.chat_key.as_ref().unwrap().as_slice().into();
+                    let try_presence = utils::decrypt_message(&enc_data, &Aes256SivAead::new(key))
+                        .await
+                        .ok()
+                        .and_then(|data| serde_json::from_slice::<Presence>(&data).ok());
+                    if let Some(presence) = try_presence {
+                        if presence.state == PresenceState::Offline {
+                            client.members.remove(&presence.username);
+                            let exit = Exit {
+                                username: presence.username,
+                            };
+                            events.send(&room.id, ChatEvent::Exit(exit));
+                        } else {
+                            if let Some(member) = client.members.get_mut(&presence.username) {
+                                member.state = presence.state;
+                            }
+                            events.send(&room.id, ChatEvent::Presence(presence));
+                        }
+                    }
+                }
+                ServerData::FileAccept(accept) => {
+                    stream_file(room.clone(), accept.id, accept.username);
+                }
+                ServerData::FileChunk(chunk) => {
+                    let progress = room
+                        .client
+                        .lock()
+                        .await
+                        .transfers
+                        .receive_chunk(&chunk)
+                        .await;
+                    match progress {
+                        Ok(progress) => events.send(&room.id, ChatEvent::FileProgress(progress)),
+                        Err(err) => send_err(events.as_ref(), &room.id, err).await,
+                    }
+                }
+                ServerData::FileComplete(complete) => {
+                    let file = room.client.lock().await.transfers.finish(&complete.id);
+                    match file {
+                        Ok(file) => events.send(&room.id, ChatEvent::FileReceived(file)),
+                        Err(err) => send_err(events.as_ref(), &room.id, err).await,
+                    }
+                }
+                ServerData::Direct(direct) => {
+                    let mut client = room.client.lock().await;
+                    let opened = utils::open_direct(
+                        &direct.ephemeral_key,
+                        &direct.data,
+                        &client.direct_secret,
+                    )
+                    .await;
+                    let try_message = opened.and_then(|data| {
+                        serde_json::from_slice::<BroadcastMessage>(&data)
+                            .map_err(|_| "Invalid direct message".to_string())
+                    });
+                    match try_message {
+                        Ok(mut message) => {
+                            message.verified =
+                                verify_direct(&mut client, &direct.username, &message);
+                            expire_after(&events, &room.id, &message);
+                            events.send(
+                                &room.id,
+                                ChatEvent::Direct(DirectChat {
+                                    peer: direct.username,
+                                    message,
+                                }),
+                            );
+                        }
+                        Err(err) => {
+                            send_err(events.as_ref(), &room.id, err).await;
+                        }
+                    }
+                }
+                ServerData::JoinPending => {
+                    events.send(&room.id, ChatEvent::JoinPending);
+                }
+                ServerData::Error(err) => {
+                    events.send(&room.id, ChatEvent::Error(err.error_msg));
+                }
+                ServerData::Shutdown => {
+                    events.send(&room.id, ChatEvent::Shutdown);
+                    let mut client = room.client.lock().await;
+                    // Already gone if we were leaving or the connection dropped
+                    if let Some(write) = client.write.as_mut() {
+                        let _ = write.close().await;
+                    }
+                    client.write = None;
+                    drop(client);
+                    CLIENTS.lock().await.remove(&room.id);
+                    let _ = room.shutdown.send(());
+                }
+            }
+        }
+    }
+}
+
+pub async fn client_exit(room: &ClientRoom) {
+    let mut client = room.client.lock().await;
+    if let Some(write) = client.write.as_mut() {
+        let _ = write
+            .send(Text(serde_json::to_string(&ClientData::Exit).unwrap()))
+            .await;
+        let _ = write.close().await;
+    }
+    client.write = None;
+    drop(client);
+    CLIENTS.lock().await.remove(&room.id);
+    let _ = room.shutdown.send(());
+}
+
+pub async fn exit_all() {
+    let rooms: Vec<Arc<ClientRoom>> = CLIENTS.lock().await.values().cloned().collect();
+    for room in rooms {
+        client_exit(&room).await;
+    }
+}
+
+async fn send_err(events: &dyn EventSink, room_id: &str, err: String) {
+    events.send(room_id, ChatEvent::Error(err));
+}
+
+/// Signs the message and sends it to the room under the current chat key
+pub async fn send_message(room: &ClientRoom, mut message: UserMessage) -> Result<(), String> {
+    let mut client = room.client.lock().await;
+    let key: &Key<Aes256SivAead> = client
+        .chat_key
+        .as_ref()
+        .ok_or_else(|| "Server chat key not found".to_string())?
+        .as_slice()
+        .into();
+    let cipher = Aes256SivAead::new(key);
+
+    message.signature = utils::sign_message(&message.content, client.signing_key.as_ref().unwrap());
+    let encrypted = utils::encrypt_message(
+        serde_json::to_string(&message).unwrap(),
+        &cipher,
+        client.epoch,
+    )
+    .await
+    .map_err(|_| "Couldn't encrypt user message".to_string())?;
+    let send_data = serde_json::to_string(&ClientData::EncData(encrypted))
+        .expect("Couldn't convert send data to string");
+
+    let write = client
+        .write
+        .as_mut()
+        .ok_or_else(|| "Connection closed".to_string())?;
+    write
+        .send(Text(send_data))
+        .await
+        .map_err(|_| "Connection closed".to_string())
+}
+
+/// Sends a message only `recipient` can read, sealed to the key they advertised for this
+/// session
+pub async fn send_direct(
+    room: &ClientRoom,
+    recipient: &str,
+    content: String,
+) -> Result<(), String> {
+    if content.len() > 5000 {
+        return Err("Message too long".into());
+    }
+    let mut client = room.client.lock().await;
+    let (recipient_key, member) = client
+        .known_keys
+        .get(recipient)
+        .cloned()
+        .zip(client.members.get(recipient))
+        .ok_or_else(|| format!("{} isn't in this chat", recipient))?;
+    // The key comes through the host, only the recipient's own signature says it's theirs
+    if !utils::verify_direct_key(
+        &member.direct_key,
+        &member.direct_key_signature,
+        &recipient_key,
+    ) {
+        return Err(format!(
+            "Couldn't verify {}'s direct message key",
+            recipient
+        ));
+    }
+    let direct_key = member.direct_key.clone();
+    let signing_key = client
+        .signing_key
+        .as_ref()
+        .ok_or_else(|| "Signing key not found".to_string())?;
+    let mut message = BroadcastMessage {
+        id: Uuid::new_v4().to_string(),
+        sender: client.username.clone(),
+        signature: utils::sign_direct(&content, &recipient_key, signing_key),
+        content,
+        created: Local::now().format("%H:%M:%S").to_string(),
+        signing_key: signing_key.verifying_key().to_bytes().to_vec(),
+        verified: false,
+        ttl: client.message_ttl,
+        edited: false,
+        reactions: BTreeMap::new(),
+    };
+    let (ephemeral_key, data) =
+        utils::seal_direct(&serde_json::to_vec(&message).unwrap(), &direct_key).await?;
+    let send_data = serde_json::to_string(&ClientData::Direct(DirectMessage {
+        username: recipient.to_string(),
+        ephemeral_key,
+        data,
+    }))
+    .unwrap();
+
+    let write = client
+        .write
+        .as_mut()
+        .ok_or_else(|| "Connection closed".to_string())?;
+    write
+        .send(Text(send_data))
+        .await
+        .map_err(|_| "Connection closed".to_string())?;
+    drop(client);
+
+    message.verified = true;
+    expire_after(&room.events, &room.id, &message);
+    room.events.send(
+        &room.id,
+        ChatEvent::Direct(DirectChat {
+            peer: recipient.to_string(),
+            message,
+        }),
+    );
+    Ok(())
+}
+
+async fn send_data(room: &ClientRoom, data: &ClientData) -> Result<(), String> {
+    let mut client = room.client.lock().await;
+    let write = client
+        .write
+        .as_mut()
+        .ok_or_else(|| "Connection closed".to_string())?;
+    write
+        .send(Text(serde_json::to_string(data).unwrap()))
+        .await
+        .map_err(|_| "Connection closed".to_string())
+}
+
+/// Asks the host to edit, delete or react to a message. Edits are signed so everyone can tell
+/// the new text came from us.
+pub async fn update_message(room: &ClientRoom, mut action: MessageAction) -> Result<(), String> {
+    let client = room.client.lock().await;
+    if let MessageAction::Edit {
+        id,
+        content,
+        signature,
+    } = &mut action
+    {
+        if content.len() > 5000 {
+            return Err("Message too long".into());
+        }
+        let signing_key = client
+            .signing_key
+            .as_ref()
+            .ok_or_else(|| "Signing key not found".to_string())?;
+        *signature = utils::sign_edit(id, content, signing_key);
+    }
+    let key: &Key<Aes256SivAead> = client
+        .chat_key
+        .as_ref()
+        .ok_or_else(|| "Server chat key not found".to_string())?
+        .as_slice()
+        .into();
+    let encrypted = utils::encrypt_data(
+        &serde_json::to_vec(&action).unwrap(),
+        &Aes256SivAead::new(key),
+        client.epoch,
+    )
+    .await?;
+    drop(client);
+    send_data(room, &ClientData::MessageAction(encrypted)).await
+}
+
+/// Tells the room whether we're around or typing. The host drops repeats that come too fast.
+pub async fn send_presence(room: &ClientRoom, state: PresenceState) -> Result<(), String> {
+    if state == PresenceState::Offline {
+        return Err("Leave the chat to go offline".into());
+    }
+    let mut client = room.client.lock().await;
+    let key: &Key<Aes256SivAead> = client
+        .chat_key
+        .as_ref()
+        .ok_or_else(|| "Server chat key not found".to_string())?
+        .as_slice()
+        .into();
+    let encrypted = utils::encrypt_data(
+        &serde_json::to_vec(&state).unwrap(),
+        &Aes256SivAead::new(key),
+        client.epoch,
+    )
+    .await?;
+    let username = client.username.clone();
+    if let Some(member) = client.members.get_mut(&username) {
+        member.state = state;
+    }
+    drop(client);
+    send_data(room, &ClientData::Presence(encrypted)).await
+}
+
+/// Offers a file to everyone in the room. It's kept in memory and sent to each member who
+/// accepts it.
+pub async fn share_file(room: &ClientRoom, name: String, data: Vec<u8>) -> Result<(), String> {
+    let mut client = room.client.lock().await;
+    if client.max_file_size == 0 {
+        return Err("File sharing is turned off in this chat".into());
+    }
+    if data.len() as u64 > client.max_file_size {
+        return Err(format!(
+            "Files can be at most {} bytes in this chat",
+            client.max_file_size
+        ));
+    }
+    let key: &Key<Aes256SivAead> = client
+        .chat_key
+        .as_ref()
+        .ok_or_else(|| "Server chat key not found".to_string())?
+        .as_slice()
+        .into();
+    let cipher = Aes256SivAead::new(key);
+    let username = client.username.clone();
+    let offer = client.transfers.share(username, name, data);
+    let encrypted =
+        utils::encrypt_data(&serde_json::to_vec(&offer).unwrap(), &cipher, client.epoch).await?;
+    drop(client);
+    send_data(room, &ClientData::FileOffer(encrypted)).await
+}
+
+/// Asks whoever offered a file to send it to us
+pub async fn accept_file(room: &ClientRoom, id: &str) -> Result<(), String> {
+    room.client.lock().await.transfers.accept(id)?;
+    let accept = ClientData::FileAccept(FileAccept {
+        id: id.to_string(),
+        username: String::new(),
+    });
+    send_data(room, &accept).await
+}
+
+/// Sends one of our files to a member who accepted it, through the host
+fn stream_file(room: Arc<ClientRoom>, id: String, recipient: String) {
+    tokio::spawn(async move {
+        if let Err(err) = send_file_chunks(&room, &id, &recipient).await {
+            println!("Couldn't send file to {}: {:?}", recipient, err);
+        }
+    });
+}
+
+async fn send_file_chunks(room: &ClientRoom, id: &str, recipient: &str) -> Result<(), String> {
+    let (offer, data) = room
+        .client
+        .lock()
+        .await
+        .transfers
+        .outgoing(id)
+        .ok_or_else(|| "No such file".to_string())?;
+    for index in 0..files::chunk_count(offer.info.size) {
+        let chunk = ClientData::FileChunk(FileChunk {
+            id: id.to_string(),
+            username: recipient.to_string(),
+            index,
+            data: files::encrypt_chunk(&offer, &data, index).await?,
+        });
+        send_data(room, &chunk).await?;
+        room.events.send(
+            &room.id,
+            ChatEvent::FileProgress(FileProgress {
+                id: id.to_string(),
+                peer: recipient.to_string(),
+                outgoing: true,
+                bytes: ((index as u64 + 1) * files::CHUNK_SIZE as u64).min(offer.info.size),
+                size: offer.info.size,
+            }),
+        );
+    }
+    let complete = ClientData::FileComplete(FileComplete {
+        id: id.to_string(),
+        username: recipient.to_string(),
+    });
+    send_data(room, &complete).await
+}
+
+/// Offers a file in a room we've joined
+pub async fn share(room_id: &str, name: String, data: Vec<u8>) -> Result<(), String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    share_file(&room, name, data).await
+}
+
+/// Downloads a file offered in a room we've joined
+pub async fn accept(room_id: &str, file_id: &str) -> Result<(), String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    accept_file(&room, file_id).await
+}
+
+/// Sends a direct message from a room we've joined
+pub async fn direct(room_id: &str, recipient: &str, content: String) -> Result<(), String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    send_direct(&room, recipient, content).await
+}
+
+/// Edits, deletes or reacts to a message in a room we've joined
+pub async fn update(room_id: &str, action: MessageAction) -> Result<(), String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    update_message(&room, action).await
+}
+
+/// Tells a room we've joined whether we're around or typing
+pub async fn set_presence(room_id: &str, state: PresenceState) -> Result<(), String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    send_presence(&room, state).await
+}
+
+/// Everyone we know of in a room we've joined
+pub async fn presence(room_id: &str) -> Result<Vec<Presence>, String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    let client = room.client.lock().await;
+    Ok(client
+        .members
+        .values()
+        .map(|member| Presence {
+            username: member.username.clone(),
+            state: member.state,
+        })
+        .collect())
+}
+
+/// Everyone in a room we've joined along with their key fingerprints
+pub async fn members(room_id: &str) -> Result<Vec<Member>, String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    let client = room.client.lock().await;
+    Ok(client.members.values().cloned().collect())
+}
+
+pub async fn safety_number(room_id: &str) -> Result<String, String> {
+    let try_room = CLIENTS.lock().await.get(room_id).cloned();
+    let room = try_room.ok_or_else(|| "Not a member of this chat".to_string())?;
+    let client = room.client.lock().await;
+    match client.host_key.as_ref() {
+        Some(host_key) => Ok(utils::safety_number(host_key)),
+        None => Err("Host identity not verified yet".into()),
+    }
+}
+
+/// Connects to a room and keeps reading from it in the background until we leave or the host
+/// shuts it down. A dropped connection is picked back up under the same name. Everything the
+/// room reports goes to `events`.
+pub async fn connect_chat(
+    username: String,
+    chat_url: String,
+    password: String,
+    member_secret: [u8; 32],
+    heartbeat: Heartbeat,
+    events: Arc<dyn EventSink>,
+) -> Result<Arc<ClientRoom>, String> {
+    let target = utils::parse_join_url(chat_url, password).await?;
+    let signing_key = utils::room_member_key(&member_secret, &target);
+    let verifying_key = signing_key.verifying_key().to_bytes().to_vec();
+
+    let (shutdown, mut shutdown_rx) = broadcast::channel(1);
+    let room = Arc::new(ClientRoom {
+        id: Uuid::new_v4().to_string(),
+        url: target.url,
+        heartbeat,
+        client: Mutex::new(Client {
+            write: None,
+            handshake_secret: None,
+            handshake_pub: None,
+            session_cipher: None,
+            chat_key: None,
+            epoch: 0,
+            signing_key: Some(signing_key),
+            direct_secret: StaticSecret::random_from_rng(OsRng),
+            known_keys: HashMap::from([(username.clone(), verifying_key)]),
+            username,
+            host_fingerprint: target.host_fingerprint,
+            host_key: None,
+            max_file_size: 0,
+            message_ttl: None,
+            transfers: Transfers::default(),
+            members: BTreeMap::new(),
+            resume_token: None,
+            received: 0,
+        }),
+        shutdown,
+        events,
+    });
+    let read = start_session(&room).await?;
+    CLIENTS.lock().await.insert(room.id.clone(), room.clone());
+
+    let conn_room = room.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {},
+            _ = keep_connected(conn_room, read) => {}
+        }
+    });
+    Ok(room)
+}
+
+type ChatStream = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// Opens a socket to the host. After a drop we try to resume our old slot, otherwise (and if
+/// the host won't have it) we join under our name and key.
+async fn start_session(room: &ClientRoom) -> Result<ChatStream, String> {
+    let (ws_stream, _) = connect_async(utils::to_ws_url(&room.url))
+        .await
+        .map_err(|_| "Couldn't connect to chat".to_string())?;
+    let (write, read) = ws_stream.split();
+
+    let mut client = room.client.lock().await;
+    client.write = Some(write);
+    // Sent under the old session key, which only we and the host have
+    let resume = match (client.resume_token.take(), client.session_cipher.as_ref()) {
+        (Some(token), Some(cipher)) => Some(ClientData::Resume(Resume {
+            username: client.username.clone(),
+            token: utils::encrypt_data(&token, cipher, 0).await?,
+            received: client.received,
+        })),
+        _ => None,
+    };
+    drop(client);
+
+    match resume {
+        Some(resume_cmd) => send_data(room, &resume_cmd)
+            .await
+            .map_err(|_| "Couldn't send resume request".to_string())?,
+        None => send_join(room).await?,
+    }
+    Ok(read)
+}
+
+/// Starts over with a fresh handshake and join on the current socket
+async fn send_join(room: &ClientRoom) -> Result<(), String> {
+    let handshake_secret = EphemeralSecret::random_from_rng(OsRng);
+    let handshake_pub = PublicKey::from(&handshake_secret);
+
+    let mut client = room.client.lock().await;
+    let signing_key = client
+        .signing_key
+        .as_ref()
+        .ok_or_else(|| "Signing key not found".to_string())?;
+    let verifying_key = signing_key.verifying_key().to_bytes().to_vec();
+    let direct_key = PublicKey::from(&client.direct_secret).as_bytes().to_vec();
+    let direct_key_signature = utils::sign_direct_key(&direct_key, signing_key);
+    let handshake_cmd = ClientData::Handshake(Handshake {
+        pub_key: handshake_pub.as_bytes().to_vec(),
+        identity_key: Vec::new(),
+        signature: Vec::new(),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        max_file_size: 0,
+        message_ttl: None,
+    });
+    let join_cmd = ClientData::Join(Join {
+        username: client.username.clone(),
+        signing_key: verifying_key,
+        direct_key,
+        direct_key_signature,
+        protocol_version: protocol::PROTOCOL_VERSION,
+    });
+    client.handshake_secret = Some(handshake_secret);
+    client.handshake_pub = Some(handshake_pub);
+    client.session_cipher = None;
+    client.chat_key = None;
+    // The host sends a fresh roster once we're back in
+    client.members.clear();
+    client.resume_token = None;
+    client.received = 0;
+    drop(client);
+
+    send_data(room, &handshake_cmd)
+        .await
+        .map_err(|_| "Couldn't send handshake".to_string())?;
+    send_data(room, &join_cmd)
+        .await
+        .map_err(|_| "Couldn't send join command".to_string())
+}
+
+/// Handles everything the host sends over one connection, pinging it as we go. Returns once
+/// the socket closes or nothing has arrived within the heartbeat timeout.
+async fn run_session(room: Arc<ClientRoom>, mut read: ChatStream) {
+    let heartbeat = room.heartbeat;
+    let (tx, rx) = mpsc::unbounded_channel::<ServerData>();
+    let read_handle = tokio::spawn(async move {
+        let timeout = Duration::from_secs(heartbeat.timeout);
+        while let Ok(Some(Ok(message))) = tokio::time::timeout(timeout, read.next()).await {
+            if message.is_close() {
+                // The host dropped us, no point waiting for the socket to wind down
+                break;
+            }
+            let text = match message {
+                Message::Text(text) => text,
+                // Pings, pongs and binary frames carry nothing for us
+                _ => continue,
+            };
+            if let Ok(recv_data) = serde_json::from_str::<ServerData>(&text) {
+                if tx.send(recv_data).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    let ping_room = room.clone();
+    let ping_handle = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(heartbeat.interval.max(1)));
+        loop {
+            interval.tick().await;
+            if let Some(write) = ping_room.client.lock().await.write.as_mut() {
+                let _ = write.send(Message::Ping(Vec::new())).await;
+            }
+        }
+    });
+
+    // Ends once the reader is gone and everything it passed on has been handled
+    handle_recv_data(room.clone(), rx, room.events.clone()).await;
+    ping_handle.abort();
+    read_handle.abort();
+}
+
+const RECONNECT_ATTEMPTS: u32 = 6;
+
+/// Runs sessions until one ends for good: we never got in, we left, or the host can't be
+/// reached again
+async fn keep_connected(room: Arc<ClientRoom>, mut read: ChatStream) {
+    let mut closed = room.shutdown.subscribe();
+    loop {
+        run_session(room.clone(), read).await;
+        let joined = room.client.lock().await.chat_key.is_some();
+        if !joined || !matches!(closed.try_recv(), Err(TryRecvError::Empty)) {
+            return;
+        }
+        room.events.send(&room.id, ChatEvent::Disconnected);
+        read = match reconnect(&room).await {
+            Some(read) => read,
+            None => {
+                room.events.send(
+                    &room.id,
+                    ChatEvent::Error("Lost the connection to the chat".into()),
+                );
+                room.events.send(&room.id, ChatEvent::Shutdown);
+                room.client.lock().await.write = None;
+                CLIENTS.lock().await.remove(&room.id);
+                let _ = room.shutdown.send(());
+                return;
+            }
+        };
+        room.events.send(&room.id, ChatEvent::Reconnected);
+    }
+}
+
+/// Tries to get back into the room, backing off between attempts
+async fn reconnect(room: &ClientRoom) -> Option<ChatStream> {
+    let mut delay = Duration::from_secs(1);
+    for _ in 0..RECONNECT_ATTEMPTS {
+        tokio::time::sleep(delay).await;
+        match start_session(room).await {
+            Ok(read) => return Some(read),
+            Err(err) => println!("Couldn't reconnect: {:?}", err),
+        }
+        delay = (delay * 2).min(Duration::from_secs(30));
+    }
+    None
+}
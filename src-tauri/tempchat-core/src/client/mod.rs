@@ -0,0 +1,3 @@
+#[allow(clippy::module_inception)]
+pub mod client;
+pub mod proto;
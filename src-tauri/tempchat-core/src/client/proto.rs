@@ -0,0 +1,76 @@
+use crate::events::EventSink;
+use crate::files::Transfers;
+use crate::structs::{Heartbeat, Member};
+use aes_siv::{aead::OsRng, Aes256SivAead};
+use ed25519_dalek::SigningKey;
+use futures_util::lock::Mutex;
+use futures_util::stream::SplitSink;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+pub struct ClientRoom {
+    pub id: String,
+    /// Where the host is, kept for reconnecting
+    pub url: String,
+    pub heartbeat: Heartbeat,
+    pub client: Mutex<Client>,
+    /// Fired once we've left the room or the host shut it down
+    pub shutdown: broadcast::Sender<()>,
+    pub events: Arc<dyn EventSink>,
+}
+
+pub struct Client {
+    pub username: String,
+    pub write: Option<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>,
+    pub handshake_secret: Option<EphemeralSecret>,
+    pub handshake_pub: Option<PublicKey>,
+    pub session_cipher: Option<Aes256SivAead>,
+    pub chat_key: Option<Vec<u8>>,
+    pub epoch: u32,
+    pub signing_key: Option<SigningKey>,
+    /// Opens direct messages to us, made fresh for every session and advertised on joining
+    pub direct_secret: StaticSecret,
+    pub known_keys: HashMap<String, Vec<u8>>,
+    pub host_fingerprint: Vec<u8>,
+    pub host_key: Option<Vec<u8>>,
+    /// Largest file the host accepts, 0 when file sharing is off
+    pub max_file_size: u64,
+    /// Seconds messages live for in this chat, as set by the host
+    pub message_ttl: Option<u64>,
+    pub transfers: Transfers,
+    /// Everyone in the room, ourselves and the host included
+    pub members: BTreeMap<String, Member>,
+    /// Lets us take our slot back if the connection drops, handed out by the host on joining
+    pub resume_token: Option<Vec<u8>>,
+    /// Frames the host has sent over this slot, so a resume knows where to pick up
+    pub received: u64,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client {
+            username: String::new(),
+            write: None,
+            handshake_secret: None,
+            handshake_pub: None,
+            session_cipher: None,
+            chat_key: None,
+            epoch: 0,
+            signing_key: None,
+            direct_secret: StaticSecret::random_from_rng(OsRng),
+            known_keys: HashMap::new(),
+            host_fingerprint: Vec::new(),
+            host_key: None,
+            max_file_size: 0,
+            message_ttl: None,
+            transfers: Transfers::default(),
+            members: BTreeMap::new(),
+            resume_token: None,
+            received: 0,
+        }
+    }
+}
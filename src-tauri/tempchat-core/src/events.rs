@@ -0,0 +1,50 @@
+use crate::files::ReceivedFile;
+use crate::server::proto::Exit;
+use crate::structs::{
+    BroadcastMessage, DirectChat, FileInfo, FileProgress, JoinMessage, JoinRequest, Kick, Member,
+    MessageUpdate, Presence, ShutdownWarning,
+};
+
+/// Everything a room reports to whoever is driving it
+#[derive(Debug)]
+pub enum ChatEvent {
+    /// A decrypted message, with `verified` already set by the receiving side
+    Message(BroadcastMessage),
+    Join(JoinMessage),
+    Exit(Exit),
+    /// Everyone who was in the room when we joined
+    Roster(Vec<Member>),
+    /// Someone came online, went idle, started typing or left
+    Presence(Presence),
+    /// A message was edited, deleted or reacted to
+    MessageUpdate(MessageUpdate),
+    /// The message with this id reached the end of its TTL
+    ExpireMessage(String),
+    /// The room is about to close itself
+    ShutdownWarning(ShutdownWarning),
+    /// A direct message we sent or received
+    Direct(DirectChat),
+    /// Someone shared a file, it's only sent once we accept it
+    FileOffer(FileInfo),
+    FileProgress(FileProgress),
+    /// A file that arrived whole and matched its hash
+    FileReceived(ReceivedFile),
+    /// Someone is waiting for the host to approve their join
+    JoinRequest(JoinRequest),
+    /// Our join is waiting for the host's approval
+    JoinPending,
+    /// The host removed us from the room
+    Kicked(Kick),
+    /// We lost the host and are trying to get back in
+    Disconnected,
+    /// Back in after `Disconnected`
+    Reconnected,
+    Error(String),
+    Shutdown,
+}
+
+/// Receives the events of every room it was handed to. The desktop app forwards them to the
+/// webview, the CLI prints them and tests can simply collect them.
+pub trait EventSink: Send + Sync {
+    fn send(&self, room_id: &str, event: ChatEvent);
+}
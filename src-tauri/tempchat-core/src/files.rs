@@ -0,0 +1,211 @@
+//! Chunked file transfer, shared by the host and members. A file is offered to the room first
+//! and only sent, chunk by chunk, to whoever accepts it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use aes_siv::aead::{KeyInit, OsRng};
+use aes_siv::{Aes256SivAead, Key};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::structs::{EncData, FileChunk, FileInfo, FileOffer, FileProgress};
+use crate::utils;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// AES-SIV adds a 16 byte tag to every chunk
+pub const MAX_CHUNK_LEN: usize = CHUNK_SIZE + 16;
+
+pub fn chunk_count(size: u64) -> u32 {
+    ((size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64) as u32
+}
+
+/// A file that arrived whole and matched its hash
+#[derive(Debug)]
+pub struct ReceivedFile {
+    pub info: FileInfo,
+    pub data: Vec<u8>,
+}
+
+struct IncomingFile {
+    offer: FileOffer,
+    data: Vec<u8>,
+    next_chunk: u32,
+}
+
+/// Files this side of the room has offered, been offered or is receiving
+#[derive(Default)]
+pub struct Transfers {
+    /// Offers from others we haven't accepted yet
+    offers: HashMap<String, FileOffer>,
+    incoming: HashMap<String, IncomingFile>,
+    /// Our own files, kept until the room closes so anyone can still accept them
+    outgoing: HashMap<String, (FileOffer, Arc<Vec<u8>>)>,
+}
+
+impl Transfers {
+    /// Prepares an offer for `data` and keeps the file around to send to whoever accepts
+    pub fn share(&mut self, sender: String, name: String, data: Vec<u8>) -> FileOffer {
+        let offer = FileOffer {
+            info: FileInfo {
+                id: Uuid::new_v4().to_string(),
+                sender,
+                name,
+                size: data.len() as u64,
+            },
+            hash: Sha256::digest(&data).to_vec(),
+            key: Aes256SivAead::generate_key(&mut OsRng).to_vec(),
+        };
+        self.outgoing
+            .insert(offer.info.id.clone(), (offer.clone(), Arc::new(data)));
+        offer
+    }
+
+    pub fn offered(&mut self, offer: FileOffer) {
+        self.offers.insert(offer.info.id.clone(), offer);
+    }
+
+    pub fn outgoing(&self, id: &str) -> Option<(FileOffer, Arc<Vec<u8>>)> {
+        self.outgoing.get(id).cloned()
+    }
+
+    /// Moves an offer we've seen to the files being received
+    pub fn accept(&mut self, id: &str) -> Result<FileInfo, String> {
+        let offer = self
+            .offers
+            .remove(id)
+            .ok_or_else(|| "No such file offer".to_string())?;
+        let info = offer.info.clone();
+        self.incoming.insert(
+            id.to_string(),
+            IncomingFile {
+                offer,
+                data: Vec::new(),
+                next_chunk: 0,
+            },
+        );
+        Ok(info)
+    }
+
+    /// Decrypts the next chunk of a file we accepted. Chunks have to arrive in order since they
+    /// all come over the one socket.
+    pub async fn receive_chunk(&mut self, chunk: &FileChunk) -> Result<FileProgress, String> {
+        let incoming = self
+            .incoming
+            .get_mut(&chunk.id)
+            .ok_or_else(|| "Chunk for a file that wasn't accepted".to_string())?;
+        if chunk.index != incoming.next_chunk {
+            return Err("File chunk out of order".into());
+        }
+        let cipher = file_cipher(&incoming.offer)?;
+        let data = utils::decrypt_message(&chunk.data, &cipher).await?;
+        if (incoming.data.len() + data.len()) as u64 > incoming.offer.info.size {
+            self.incoming.remove(&chunk.id);
+            return Err("File is larger than offered".into());
+        }
+        incoming.data.extend_from_slice(&data);
+        incoming.next_chunk += 1;
+        Ok(FileProgress {
+            id: chunk.id.clone(),
+            peer: incoming.offer.info.sender.clone(),
+            outgoing: false,
+            bytes: incoming.data.len() as u64,
+            size: incoming.offer.info.size,
+        })
+    }
+
+    /// Checks a finished file against its offer
+    pub fn finish(&mut self, id: &str) -> Result<ReceivedFile, String> {
+        let incoming = self
+            .incoming
+            .remove(id)
+            .ok_or_else(|| "No such file transfer".to_string())?;
+        if incoming.data.len() as u64 != incoming.offer.info.size
+            || Sha256::digest(&incoming.data).to_vec() != incoming.offer.hash
+        {
+            return Err(format!("{} arrived damaged", incoming.offer.info.name));
+        }
+        Ok(ReceivedFile {
+            info: incoming.offer.info,
+            data: incoming.data,
+        })
+    }
+}
+
+/// Encrypts chunk `index` of `data` under the offer's key
+pub async fn encrypt_chunk(offer: &FileOffer, data: &[u8], index: u32) -> Result<EncData, String> {
+    let start = index as usize * CHUNK_SIZE;
+    let end = (start + CHUNK_SIZE).min(data.len());
+    utils::encrypt_data(&data[start..end], &file_cipher(offer)?, 0).await
+}
+
+fn file_cipher(offer: &FileOffer) -> Result<Aes256SivAead, String> {
+    if offer.key.len() != 64 {
+        return Err("Invalid file key".into());
+    }
+    let key: &Key<Aes256SivAead> = offer.key.as_slice().into();
+    Ok(Aes256SivAead::new(key))
+}
+
+/// Writes a received file into `dir` without overwriting anything already there. Only the last
+/// component of the sender's name is used, so a file can't land outside `dir`.
+pub fn save_file(dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf, String> {
+    let name = Path::new(name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| !name.starts_with('.'))
+        .unwrap_or("download");
+    let stem = Path::new(name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(name);
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| format!(".{}", extension))
+        .unwrap_or_default();
+
+    let mut path = dir.join(name);
+    let mut copy = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, copy, extension));
+        copy += 1;
+    }
+    fs::write(&path, data).map_err(|err| format!("Couldn't save {}: {}", name, err))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_count_rounds_up() {
+        assert_eq!(chunk_count(0), 0);
+        assert_eq!(chunk_count(1), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64 + 1), 2);
+        assert_eq!(chunk_count(DEFAULT_MAX_FILE_SIZE), 160);
+    }
+
+    #[test]
+    fn save_file_stays_in_dir() {
+        let dir = std::env::temp_dir().join(format!("tempchat-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let escaped = save_file(&dir, "../../etc/passwd", b"x").unwrap();
+        assert_eq!(escaped, dir.join("passwd"));
+        let hidden = save_file(&dir, ".bashrc", b"x").unwrap();
+        assert_eq!(hidden, dir.join("download"));
+        let first = save_file(&dir, "notes.txt", b"one").unwrap();
+        let second = save_file(&dir, "notes.txt", b"two").unwrap();
+        assert_eq!(first, dir.join("notes.txt"));
+        assert_eq!(second, dir.join("notes (1).txt"));
+        assert_eq!(fs::read(&first).unwrap(), b"one");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
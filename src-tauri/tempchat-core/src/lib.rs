@@ -0,0 +1,7 @@
+pub mod client;
+pub mod events;
+pub mod files;
+pub mod protocol;
+pub mod server;
+pub mod structs;
+pub mod utils;
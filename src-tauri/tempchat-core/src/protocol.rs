@@ -0,0 +1,76 @@
+//! Frames exchanged between the host and its members. Both sides and the relay use these
+//! definitions, so a variant can't be added on one side without the other knowing about it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::structs::{
+    DirectMessage, EncData, Error, FileAccept, FileChunk, FileComplete, Handshake, Join,
+    JoinMessage, KeyMessage, Kick, Resume, Resumed, ShutdownWarning,
+};
+
+/// Version spoken by this build. Bump it whenever a frame changes in a way older peers can't
+/// ignore, and raise `MIN_PROTOCOL_VERSION` once the old layout is no longer understood.
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+pub fn supported_version(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Sent by the host to a member
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ServerData {
+    Error(Error),
+    Shutdown,
+    Handshake(Handshake),
+    JoinMessage(JoinMessage),
+    KeyMessage(KeyMessage),
+    KeyRotation(KeyMessage),
+    EncData(EncData),
+    /// Earlier messages for a member that just joined, under their session key
+    History(Vec<EncData>),
+    ShutdownWarning(ShutdownWarning),
+    Kick(Kick),
+    /// The host has to approve the join before the key is sent
+    JoinPending,
+    Direct(DirectMessage),
+    /// A `FileOffer` under the group key
+    FileOffer(EncData),
+    FileAccept(FileAccept),
+    FileChunk(FileChunk),
+    FileComplete(FileComplete),
+    /// A checked `MessageUpdate` under the group key
+    MessageUpdate(EncData),
+    /// A `Presence` under the group key
+    Presence(EncData),
+    /// Everyone already in the room as `Member`s, under the new member's session key. Sent
+    /// right after their first `KeyMessage`, joins and leaves after that keep it current.
+    Roster(EncData),
+    /// Back in the old slot, followed by whatever was missed
+    Resumed(Resumed),
+    /// The slot is gone, the member has to join again
+    ResumeFailed,
+}
+
+/// Sent by a member to the host
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ClientData {
+    EncData(EncData),
+    Handshake(Handshake),
+    Join(Join),
+    Exit,
+    Direct(DirectMessage),
+    /// A `FileOffer` under the group key
+    FileOffer(EncData),
+    FileAccept(FileAccept),
+    FileChunk(FileChunk),
+    FileComplete(FileComplete),
+    /// A `MessageAction` under the group key
+    MessageAction(EncData),
+    /// A `PresenceState` under the group key
+    Presence(EncData),
+    /// Asks for our old slot back after a drop
+    Resume(Resume),
+}
@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::events::{ChatEvent, EventSink};
+use crate::files::Transfers;
+use crate::protocol::ClientData;
+use crate::server::outbox::{QueueStats, SlowConsumer};
+use crate::server::proto::{ChatData, History, Room, Transport};
+use crate::server::socket::handle::{
+    accept_file, approve_join, chat_shutdown, check_heartbeats, close_client, connection_lost,
+    deny_join, handle_message, handle_message_action, kick_member, member_list, presence_list,
+    queue_stats, send_direct, send_host_presence, share_file, warn_shutdown,
+};
+use crate::server::socket::handle::{get_room, handle_connection, register_room, touch_client};
+use crate::server::tunnel::{LocalTunnel, NoTunnel, RelayTunnel, TunnelProvider, TunnelRequest};
+use crate::structs::{
+    Heartbeat, Member, MessageAction, Presence, PresenceState, RoomHandle, ShutdownWarning,
+};
+use crate::utils::{self, JoinTarget, KdfStrength};
+use aes_siv::{
+    aead::{KeyInit, OsRng},
+    Aes256SivAead,
+};
+use ed25519_dalek::SigningKey;
+use futures_util::StreamExt;
+use nanoid::nanoid;
+use rand::Rng;
+use tokio::sync::mpsc;
+use tokio::{net::TcpListener, sync::broadcast};
+use x25519_dalek::StaticSecret;
+
+async fn handle_channel_message(
+    room: Arc<Room>,
+    mut rx: mpsc::UnboundedReceiver<(ClientData, String)>,
+) {
+    loop {
+        let data = rx.recv().await;
+        if let Some((message, uid)) = data {
+            if let Err(err) = handle_message(&room, &message, &uid).await {
+                // Only the offending client is dropped, the room keeps serving everyone else
+                println!("Closing client after error: {:?}", err);
+                close_client(&room, &uid).await;
+            }
+        }
+    }
+}
+
+/// One address in each private range, a machine may only have a route to some of them
+const LAN_PROBES: [&str; 3] = ["10.255.255.255:1", "172.31.255.255:1", "192.168.255.255:1"];
+
+/// Finds the address of the interface that routes to the local network. Connecting a UDP
+/// socket doesn't send anything, it only makes the OS pick the outgoing interface.
+fn lan_address() -> Result<IpAddr, String> {
+    LAN_PROBES
+        .iter()
+        .find_map(|probe| {
+            let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+            socket.connect(probe).ok()?;
+            let ip = socket.local_addr().ok()?.ip();
+            (!ip.is_unspecified() && !ip.is_loopback()).then_some(ip)
+        })
+        .ok_or_else(|| "Couldn't determine the LAN address, pick one instead".into())
+}
+
+fn tunnel_provider(transport: &Transport) -> Box<dyn TunnelProvider> {
+    match transport {
+        Transport::Tunnel { server: None } => Box::new(LocalTunnel::default()),
+        Transport::Tunnel {
+            server: Some(server),
+        } => Box::new(LocalTunnel {
+            server: server.clone(),
+        }),
+        Transport::Relay { server } => Box::new(RelayTunnel {
+            server: server.clone(),
+        }),
+        Transport::Lan { .. } => Box::new(NoTunnel),
+    }
+}
+
+/// Pings members and drops the ones that stopped answering
+async fn keep_alive(room: Arc<Room>, heartbeat: Heartbeat) {
+    let mut interval = tokio::time::interval(Duration::from_secs(heartbeat.interval.max(1)));
+    loop {
+        interval.tick().await;
+        check_heartbeats(&room, Duration::from_secs(heartbeat.timeout)).await;
+    }
+}
+
+/// Warnings go out once this many seconds are left before a room closes itself
+const SHUTDOWN_WARNINGS: [u64; 2] = [60, 10];
+
+/// Closes the room once it has outlived `max_lifetime` or sat idle for `idle_timeout` seconds,
+/// warning everyone as the deadline gets close.
+async fn watch_lifetime(room: Arc<Room>, max_lifetime: Option<u64>, idle_timeout: Option<u64>) {
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut warnings_sent = 0;
+    loop {
+        interval.tick().await;
+        let last_activity = room.chat_data.lock().await.last_activity;
+        // Deadlines too far out to represent never come
+        let lifetime_end =
+            max_lifetime.and_then(|secs| started.checked_add(Duration::from_secs(secs)));
+        let idle_end =
+            idle_timeout.and_then(|secs| last_activity.checked_add(Duration::from_secs(secs)));
+        let (deadline, idle) = match (lifetime_end, idle_end) {
+            (Some(lifetime_end), Some(idle_end)) if idle_end < lifetime_end => (idle_end, true),
+            (Some(lifetime_end), _) => (lifetime_end, false),
+            (None, Some(idle_end)) => (idle_end, true),
+            (None, None) => return,
+        };
+
+        let seconds_left = deadline.saturating_duration_since(Instant::now()).as_secs();
+        if seconds_left == 0 {
+            room.events.send(&room.id, ChatEvent::Shutdown);
+            chat_shutdown(&room).await;
+            return;
+        }
+        // Activity pushed the deadline back, so the warnings start over
+        if warnings_sent > 0 && seconds_left > SHUTDOWN_WARNINGS[warnings_sent - 1] {
+            warnings_sent = 0;
+        }
+        let mut warn = false;
+        while warnings_sent < SHUTDOWN_WARNINGS.len()
+            && seconds_left <= SHUTDOWN_WARNINGS[warnings_sent]
+        {
+            warnings_sent += 1;
+            warn = true;
+        }
+        if warn {
+            warn_shutdown(&room, ShutdownWarning { seconds_left, idle }).await;
+        }
+    }
+}
+
+pub struct HostOptions {
+    pub username: String,
+    pub user_limit: i32,
+    pub password: String,
+    pub kdf_strength: KdfStrength,
+    pub transport: Transport,
+    /// Keep recent messages and replay them to members who join later
+    pub share_history: bool,
+    /// Seconds after which messages disappear for everyone
+    pub message_ttl: Option<u64>,
+    /// Seconds after which the room closes itself no matter what
+    pub max_lifetime: Option<u64>,
+    /// Seconds without messages or joins after which the room closes itself
+    pub idle_timeout: Option<u64>,
+    /// Hold joins until the host approves them
+    pub require_approval: bool,
+    /// Largest file members may share, 0 turns file sharing off
+    pub max_file_size: u64,
+    /// Members who go quiet for longer than the timeout are dropped
+    pub heartbeat: Heartbeat,
+    /// What to do about members whose connection falls behind
+    pub slow_consumer: SlowConsumer,
+    /// Exposes the room instead of the provider `transport` picks, for embedders with a tunnel
+    /// of their own
+    pub tunnel_provider: Option<Box<dyn TunnelProvider>>,
+}
+
+/// Opens a room and serves it in the background until `chat_shutdown` is called on it.
+/// Everything the room reports goes to `events`.
+pub async fn host_chat(
+    options: HostOptions,
+    identity: SigningKey,
+    events: Arc<dyn EventSink>,
+) -> Result<(RoomHandle, Arc<Room>), String> {
+    let transport = options.transport;
+    // Members on the LAN connect straight to the listener, everyone else comes in through a
+    // tunnel on this machine
+    let (bind_host, host) = match &transport {
+        Transport::Lan {
+            address: Some(address),
+        } => ("0.0.0.0", address.clone()),
+        Transport::Lan { address: None } => ("0.0.0.0", lan_address()?.to_string()),
+        _ => ("127.0.0.1", "127.0.0.1".to_string()),
+    };
+    let port = rand::thread_rng().gen_range(10_000..=20_000);
+    let addr = format!("{}:{}", bind_host, port);
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|_| format!("Unable to bind to port {}", port))?;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Aes256SivAead::new(&key);
+    let host_fingerprint = utils::fingerprint(identity.verifying_key().as_bytes());
+
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let alphabet: [char; 36] = [
+        '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h',
+        'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+    ];
+    let chat_id = nanoid!(16, &alphabet);
+    let provider = match options.tunnel_provider {
+        Some(provider) => provider,
+        None => tunnel_provider(&transport),
+    };
+    let chat_url = provider
+        .open(TunnelRequest {
+            room_id: chat_id.clone(),
+            local_host: host,
+            local_port: port,
+            max_conn: options.user_limit as u8 + 5, //Allow more socket connections
+            shutdown: notify_shutdown.clone(),
+        })
+        .await?;
+
+    let join_target = JoinTarget {
+        url: chat_url,
+        host_fingerprint,
+    };
+    let join_url =
+        utils::create_join_url(join_target, options.password, options.kdf_strength).await?;
+
+    let room = register_room(
+        chat_id.clone(),
+        ChatData {
+            key_cipher: cipher,
+            key: key.to_vec(),
+            epoch: 0,
+            user_limit: options.user_limit,
+            host_username: options.username,
+            previous_cipher: None,
+            rotated_at: Instant::now(),
+            signing_key: identity,
+            direct_secret: StaticSecret::random_from_rng(OsRng),
+            peer_map: HashMap::new(),
+            history: options.share_history.then(History::default),
+            message_ttl: options.message_ttl,
+            last_activity: Instant::now(),
+            banned: HashSet::new(),
+            require_approval: options.require_approval,
+            max_file_size: options.max_file_size,
+            file_routes: HashMap::new(),
+            transfers: Transfers::default(),
+            file_relays: HashMap::new(),
+            message_senders: HashMap::new(),
+            host_presence: PresenceState::Online,
+            slow_consumer: options.slow_consumer,
+        },
+        notify_shutdown,
+        events,
+    )
+    .await;
+
+    if options.max_lifetime.is_some() || options.idle_timeout.is_some() {
+        let watch_room = room.clone();
+        let mut watch_shutdown = room.shutdown.subscribe();
+        let (max_lifetime, idle_timeout) = (options.max_lifetime, options.idle_timeout);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = watch_lifetime(watch_room, max_lifetime, idle_timeout) => {}
+                _ = watch_shutdown.recv() => {}
+            }
+        });
+    }
+
+    let heartbeat = options.heartbeat;
+    let serve_room = room.clone();
+    let mut shutdown_rx = room.shutdown.subscribe();
+    tokio::spawn(async move {
+        let room = serve_room;
+        let (tx, rx) = mpsc::unbounded_channel::<(ClientData, String)>();
+
+        let conn_room = room.clone();
+        let conn_handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                let conn_room = conn_room.clone();
+                tokio::spawn(async move {
+                    if let Ok(Some((mut read, uid))) = handle_connection(&conn_room, stream).await {
+                        while let Some(Ok(content)) = read.next().await {
+                            touch_client(&conn_room, &uid).await;
+                            if let Ok(message) =
+                                serde_json::from_str::<ClientData>(&content.to_string())
+                            {
+                                tx.send((message, uid.clone()))
+                                    .expect("Couldn't send message over channel");
+                            }
+                        }
+                        // Gone without saying goodbye
+                        connection_lost(&conn_room, &uid).await;
+                    } else {
+                        println!("Client connection error");
+                    }
+                });
+            }
+        });
+
+        tokio::select! {
+            _ = handle_channel_message(room.clone(), rx) => {}
+            _ = keep_alive(room.clone(), heartbeat) => {}
+            _ = shutdown_rx.recv() => {
+                conn_handle.abort();
+            }
+        }
+    });
+
+    Ok((
+        RoomHandle {
+            room_id: chat_id,
+            join_url,
+        },
+        room,
+    ))
+}
+
+/// Removes `username` from a room we're hosting. Banning keeps them out for the rest of its life.
+pub async fn kick(room_id: &str, username: &str, reason: String, ban: bool) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    kick_member(&room, username, reason, ban).await
+}
+
+/// Lets a waiting joiner into a room we're hosting
+pub async fn approve(room_id: &str, username: &str) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    approve_join(&room, username).await
+}
+
+/// Turns a waiting joiner away from a room we're hosting
+pub async fn deny(room_id: &str, username: &str) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    deny_join(&room, username).await
+}
+
+/// Sends a direct message to a member of a room we're hosting
+pub async fn direct(room_id: &str, username: &str, content: String) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    send_direct(&room, username, content).await
+}
+
+/// Offers a file to everyone in a room we're hosting
+pub async fn share(room_id: &str, name: String, data: Vec<u8>) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    share_file(&room, name, data).await
+}
+
+/// Downloads a file offered in a room we're hosting
+pub async fn accept(room_id: &str, file_id: &str) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    accept_file(&room, file_id).await
+}
+
+/// Edits, deletes or reacts to a message in a room we're hosting
+pub async fn update(room_id: &str, action: MessageAction) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    handle_message_action(&room, &action, None).await
+}
+
+/// Tells everyone in a room we're hosting whether we're around or typing
+pub async fn set_presence(room_id: &str, state: PresenceState) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    send_host_presence(&room, state).await
+}
+
+/// Everyone in a room we're hosting, ourselves included
+pub async fn presence(room_id: &str) -> Result<Vec<Presence>, String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    Ok(presence_list(&room).await)
+}
+
+/// Everyone in a room we're hosting along with their key fingerprints
+pub async fn members(room_id: &str) -> Result<Vec<Member>, String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    let chat_data = room.chat_data.lock().await;
+    Ok(member_list(&chat_data))
+}
+
+/// How far behind each member's connection is in a room we're hosting
+pub async fn queues(room_id: &str) -> Result<Vec<QueueStats>, String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    Ok(queue_stats(&room).await)
+}
+
+pub async fn safety_number(room_id: &str) -> Result<String, String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Not hosting this chat".to_string())?;
+    let chat_data = room.chat_data.lock().await;
+    Ok(utils::safety_number(
+        chat_data.signing_key.verifying_key().as_bytes(),
+    ))
+}
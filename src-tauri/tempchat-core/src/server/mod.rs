@@ -0,0 +1,5 @@
+pub mod chat;
+pub mod outbox;
+pub mod proto;
+pub mod socket;
+pub mod tunnel;
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures_util::stream::SplitSink;
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Bytes a client may have waiting before `SlowConsumer` kicks in. Well under
+/// `RESUME_BUFFER_BYTES`, so a member cut off for falling behind can still resume.
+pub const OUTBOX_LIMIT_BYTES: usize = 1024 * 1024;
+
+/// The connection was cut, nothing more is taken for it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Connection closed")
+    }
+}
+
+/// What happens to a client whose connection can't keep up with the room
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SlowConsumer {
+    /// Throw away their oldest queued chat messages and presence updates, they stay connected
+    /// but miss those. If only frames the room can't do without are left, cut the connection.
+    DropOldest,
+    /// Cut the connection. Members keep their slot and catch up once they resume.
+    #[default]
+    Disconnect,
+}
+
+/// How far behind a member's connection is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueStats {
+    pub username: String,
+    pub frames: usize,
+    pub bytes: usize,
+    /// Most bytes that were ever waiting at once
+    pub peak_bytes: usize,
+    /// Frames thrown away under `SlowConsumer::DropOldest`
+    pub dropped: u64,
+    pub connected: bool,
+}
+
+/// Frames waiting to go out to one client. A writer task of its own drains it, so nothing
+/// holding the room lock ever waits on a socket.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    policy: SlowConsumer,
+    /// Wakes the writer
+    queued: Notify,
+    /// Wakes whoever is waiting in `ready`
+    drained: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    frames: VecDeque<Queued>,
+    bytes: usize,
+    peak_bytes: usize,
+    dropped: u64,
+    /// Room on top of the limit for frames replayed by `catch_up`, used up as they go out
+    allowance: usize,
+    /// No more frames are taken, the writer closes the socket once the rest are out
+    closed: bool,
+}
+
+struct Queued {
+    message: Message,
+    /// Safe to throw away under `SlowConsumer::DropOldest`
+    droppable: bool,
+}
+
+impl Outbox {
+    /// Starts the writer task for a new connection
+    pub fn spawn(
+        write: SplitSink<WebSocketStream<TcpStream>, Message>,
+        policy: SlowConsumer,
+    ) -> Self {
+        let outbox = Outbox {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                policy,
+                queued: Notify::new(),
+                drained: Notify::new(),
+            }),
+        };
+        tokio::spawn(outbox.clone().write_all(write));
+        outbox
+    }
+
+    /// Queues a frame without waiting for the socket. Only `droppable` frames are ever thrown
+    /// away, the rest either go out or the connection is cut.
+    pub fn push(&self, message: Message, droppable: bool) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        state.bytes += message.len();
+        state.frames.push_back(Queued { message, droppable });
+        while state.bytes > OUTBOX_LIMIT_BYTES + state.allowance {
+            let oldest_droppable = match self.shared.policy {
+                SlowConsumer::DropOldest => state.frames.iter().position(|queued| queued.droppable),
+                SlowConsumer::Disconnect => None,
+            };
+            match oldest_droppable.and_then(|index| state.frames.remove(index)) {
+                Some(oldest) => {
+                    state.bytes -= oldest.message.len();
+                    state.dropped += 1;
+                }
+                None => {
+                    // Flushing into a socket that's this far behind would only keep it stuck
+                    println!("Client can't keep up, dropping their connection");
+                    state.frames.clear();
+                    state.bytes = 0;
+                    state.closed = true;
+                    drop(state);
+                    self.shared.queued.notify_one();
+                    self.shared.drained.notify_waiters();
+                    return Err(Closed);
+                }
+            }
+        }
+        state.peak_bytes = state.peak_bytes.max(state.bytes);
+        drop(state);
+        self.shared.queued.notify_one();
+        Ok(())
+    }
+
+    /// Queues frames a member missed while they were away. They can go past the limit, a
+    /// resume replays everything at once and shouldn't count as falling behind.
+    pub fn catch_up(&self, messages: Vec<Message>) -> Result<(), Closed> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(Closed);
+        }
+        for message in messages {
+            state.bytes += message.len();
+            state.allowance += message.len();
+            state.frames.push_back(Queued {
+                message,
+                droppable: false,
+            });
+        }
+        state.peak_bytes = state.peak_bytes.max(state.bytes);
+        drop(state);
+        self.shared.queued.notify_one();
+        Ok(())
+    }
+
+    /// Sends whatever is still queued, then closes the socket
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.queued.notify_one();
+        self.shared.drained.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    /// Waits until at most half the limit is queued, so bulk senders like file transfers go
+    /// at the pace of the connection instead of running into `SlowConsumer`
+    pub async fn ready(&self) {
+        loop {
+            let drained = self.shared.drained.notified();
+            {
+                let state = self.shared.state.lock().unwrap();
+                if state.closed || state.bytes <= OUTBOX_LIMIT_BYTES / 2 {
+                    return;
+                }
+            }
+            drained.await;
+        }
+    }
+
+    pub fn stats(&self, username: String, connected: bool) -> QueueStats {
+        let state = self.shared.state.lock().unwrap();
+        QueueStats {
+            username,
+            frames: state.frames.len(),
+            bytes: state.bytes,
+            peak_bytes: state.peak_bytes,
+            dropped: state.dropped,
+            connected,
+        }
+    }
+
+    async fn write_all(self, mut write: SplitSink<WebSocketStream<TcpStream>, Message>) {
+        loop {
+            let next = {
+                let mut state = self.shared.state.lock().unwrap();
+                let next = state.frames.pop_front().map(|queued| queued.message);
+                if let Some(message) = next.as_ref() {
+                    state.bytes -= message.len();
+                    state.allowance = state.allowance.saturating_sub(message.len());
+                }
+                (next, state.closed)
+            };
+            match next {
+                (Some(message), _) => {
+                    self.shared.drained.notify_waiters();
+                    if let Err(err) = write.send(message).await {
+                        println!("Error writing to client: {:?}", err);
+                        let mut state = self.shared.state.lock().unwrap();
+                        state.frames.clear();
+                        state.bytes = 0;
+                        state.closed = true;
+                        drop(state);
+                        self.shared.drained.notify_waiters();
+                        return;
+                    }
+                }
+                (None, true) => {
+                    let _ = write.close().await;
+                    return;
+                }
+                (None, false) => self.shared.queued.notified().await,
+            }
+        }
+    }
+}
+
+/// The server half of a websocket to a peer that never reads, and the peer to keep it open
+#[cfg(test)]
+pub(crate) async fn test_socket() -> (
+    SplitSink<WebSocketStream<TcpStream>, Message>,
+    WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>,
+) {
+    use futures_util::StreamExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (peer, accepted) = tokio::join!(
+        tokio_tungstenite::connect_async(format!("ws://{}", addr)),
+        async { tokio_tungstenite::accept_async(listener.accept().await.unwrap().0).await }
+    );
+    let (write, _) = accepted.unwrap().split();
+    (write, peer.unwrap().0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The writer only runs once a test awaits, so pushes below pile up in the queue
+
+    fn frame(len: usize) -> Message {
+        Message::Text("x".repeat(len))
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_control_frames() {
+        let (write, _peer) = test_socket().await;
+        let outbox = Outbox::spawn(write, SlowConsumer::DropOldest);
+        outbox.push(frame(1024), false).unwrap();
+        for _ in 0..32 {
+            outbox.push(frame(64 * 1024), true).unwrap();
+        }
+
+        let state = outbox.shared.state.lock().unwrap();
+        assert!(state.dropped > 0);
+        assert!(state.bytes <= OUTBOX_LIMIT_BYTES);
+        assert!(!state.frames[0].droppable);
+        assert!(!state.closed);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_disconnects_without_droppable_frames() {
+        let (write, _peer) = test_socket().await;
+        let outbox = Outbox::spawn(write, SlowConsumer::DropOldest);
+        let overflowed = (0..32).any(|_| outbox.push(frame(64 * 1024), false).is_err());
+        assert!(overflowed);
+        assert!(outbox.is_closed());
+        assert_eq!(outbox.stats(String::new(), false).dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn disconnect_never_drops() {
+        let (write, _peer) = test_socket().await;
+        let outbox = Outbox::spawn(write, SlowConsumer::Disconnect);
+        let overflowed = (0..32).any(|_| outbox.push(frame(64 * 1024), true).is_err());
+        assert!(overflowed);
+        assert!(outbox.is_closed());
+        assert_eq!(outbox.stats(String::new(), false).dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn catch_up_goes_past_the_limit() {
+        let (write, _peer) = test_socket().await;
+        let outbox = Outbox::spawn(write, SlowConsumer::Disconnect);
+        let missed = (0..32).map(|_| frame(64 * 1024)).collect();
+        outbox.catch_up(missed).unwrap();
+        outbox.push(frame(1024), false).unwrap();
+        assert!(!outbox.is_closed());
+        assert!(outbox.stats(String::new(), true).bytes > OUTBOX_LIMIT_BYTES);
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::events::EventSink;
+use crate::files::{Transfers, DEFAULT_MAX_FILE_SIZE};
+use crate::protocol::ServerData;
+use crate::server::outbox::{Closed, Outbox, SlowConsumer};
+use crate::structs::{EncData, Join, PresenceState};
+
+use aes_siv::{aead::KeyInit, Aes256SivAead};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures_util::lock::Mutex;
+use futures_util::stream::SplitSink;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use x25519_dalek::StaticSecret;
+
+pub struct Client {
+    pub username: String,
+    /// Frames on their way out, written by a task of their own
+    pub outbox: Outbox,
+    pub registered: bool,
+    pub session_cipher: Option<Aes256SivAead>,
+    pub signing_key: Option<VerifyingKey>,
+    /// What the member advertised in `Join::direct_key`
+    pub direct_key: Vec<u8>,
+    pub direct_key_signature: Vec<u8>,
+    /// Negotiated when the client joins
+    pub protocol_version: u32,
+    /// Join waiting for the host's approval
+    pub pending_join: Option<Join>,
+    pub presence: PresenceState,
+    /// When `presence` was last passed on, for throttling
+    pub presence_at: Instant,
+    /// A change came in too soon after the last one and goes out once the throttle is up
+    pub presence_pending: bool,
+    /// Last frame of any kind, pongs included
+    pub last_seen: Instant,
+    /// Hash of the token that lets this member take the slot back after a drop
+    pub resume_token: Vec<u8>,
+    /// Set while the connection is down and the slot is held for the member to resume
+    pub disconnected_at: Option<Instant>,
+    /// Frames sent over the life of the slot, the last few kept so they can be replayed along
+    /// with when they stop being worth replaying
+    pub sent_count: u64,
+    pub sent: VecDeque<(String, Option<Instant>)>,
+    sent_bytes: usize,
+}
+
+/// A dropped member has this long to resume before they're treated as gone
+pub const RESUME_GRACE: Duration = Duration::from_secs(120);
+/// Frames kept per member for replaying after a resume
+pub const RESUME_BUFFER_BYTES: usize = 4 * 1024 * 1024;
+/// How long frames under the group key from before a rotation are still taken. Members may
+/// have sent them before the new key reached them.
+pub const PREVIOUS_KEY_GRACE: Duration = Duration::from_secs(10);
+
+impl Client {
+    pub fn new(
+        write: SplitSink<WebSocketStream<TcpStream>, Message>,
+        slow_consumer: SlowConsumer,
+    ) -> Self {
+        Client {
+            username: String::new(),
+            outbox: Outbox::spawn(write, slow_consumer),
+            registered: false,
+            session_cipher: None,
+            signing_key: None,
+            direct_key: Vec::new(),
+            direct_key_signature: Vec::new(),
+            protocol_version: 0,
+            pending_join: None,
+            presence: PresenceState::Online,
+            presence_at: Instant::now(),
+            presence_pending: false,
+            last_seen: Instant::now(),
+            resume_token: Vec::new(),
+            disconnected_at: None,
+            sent_count: 0,
+            sent: VecDeque::new(),
+            sent_bytes: 0,
+        }
+    }
+
+    /// Queues a frame and keeps a copy for replaying. While the connection is down the frame is
+    /// only kept.
+    pub fn send(&mut self, frame: String) -> Result<(), Closed> {
+        self.queue(frame, false, None)
+    }
+
+    /// Like `send`, for a message that shouldn't be replayed after `expires`
+    pub fn send_expiring(&mut self, frame: String, expires: Option<Instant>) -> Result<(), Closed> {
+        self.queue(frame, false, expires)
+    }
+
+    /// Like `send_expiring`, for chat messages and presence updates a member that can't keep
+    /// up may miss under `SlowConsumer::DropOldest`
+    pub fn send_lossy(&mut self, frame: String, expires: Option<Instant>) -> Result<(), Closed> {
+        self.queue(frame, true, expires)
+    }
+
+    fn queue(
+        &mut self,
+        frame: String,
+        droppable: bool,
+        expires: Option<Instant>,
+    ) -> Result<(), Closed> {
+        self.sent_count += 1;
+        self.sent_bytes += frame.len();
+        self.sent.push_back((frame.clone(), expires));
+        while self.sent_bytes > RESUME_BUFFER_BYTES {
+            if let Some((oldest, _)) = self.sent.pop_front() {
+                self.sent_bytes -= oldest.len();
+            }
+        }
+        if self.disconnected_at.is_some() {
+            return Ok(());
+        }
+        let res = self.outbox.push(Message::Text(frame), droppable);
+        if res.is_err() && self.registered {
+            // Fell too far behind or the socket broke, the slot is held like for any other drop
+            self.disconnected_at = Some(Instant::now());
+            return Ok(());
+        }
+        res
+    }
+
+    /// Frames sent after the first `received` that haven't expired yet, with how many were
+    /// left out for expiring. `None` if some of them are no longer kept.
+    pub fn sent_since(&self, received: u64) -> Option<(Vec<String>, u64)> {
+        let kept_from = self.sent_count - self.sent.len() as u64;
+        if received < kept_from || received > self.sent_count {
+            return None;
+        }
+        let now = Instant::now();
+        let mut skipped = 0;
+        let frames = self
+            .sent
+            .iter()
+            .skip((received - kept_from) as usize)
+            .filter(|(_, expires)| {
+                let expired = expires.is_some_and(|expires| expires <= now);
+                skipped += expired as u64;
+                !expired
+            })
+            .map(|(frame, _)| frame.clone())
+            .collect();
+        Some((frames, skipped))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Exit {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Transport {
+    /// Expose the room through a localtunnel server, loca.lt unless another is given
+    Tunnel { server: Option<String> },
+    /// Register the room with a self-hosted relay
+    Relay { server: String },
+    /// Listen on every interface and let members connect directly to `address`, the LAN
+    /// interface's address unless another is given
+    Lan { address: Option<String> },
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Tunnel { server: None }
+    }
+}
+
+pub struct Room {
+    pub id: String,
+    pub chat_data: Mutex<ChatData>,
+    /// Fired once the room has shut down so the tunnel and anything listening for the room can stop
+    pub shutdown: broadcast::Sender<()>,
+    pub events: Arc<dyn EventSink>,
+}
+
+/// Messages kept for members who join later, at most `HISTORY_LIMIT` of them. They're held
+/// under a key that never leaves the host and re-encrypted for each new member.
+pub struct History {
+    pub cipher: Aes256SivAead,
+    pub messages: VecDeque<StoredMessage>,
+}
+
+pub struct StoredMessage {
+    pub id: String,
+    pub expires: Option<Instant>,
+    pub data: EncData,
+}
+
+pub const HISTORY_LIMIT: usize = 100;
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            cipher: Aes256SivAead::new(&Aes256SivAead::generate_key(&mut OsRng)),
+            messages: VecDeque::new(),
+        }
+    }
+}
+
+pub struct ChatData {
+    pub key_cipher: Aes256SivAead,
+    pub key: Vec<u8>,
+    pub epoch: u32,
+    pub peer_map: HashMap<String, Client>,
+    pub user_limit: i32,
+    pub host_username: String,
+    /// Group key from before the last rotation, see `PREVIOUS_KEY_GRACE`
+    pub previous_cipher: Option<Aes256SivAead>,
+    pub rotated_at: Instant,
+    pub signing_key: SigningKey,
+    /// Opens direct messages to the host, made fresh for every room
+    pub direct_secret: StaticSecret,
+    /// Only kept when the host chose to share history
+    pub history: Option<History>,
+    /// Seconds every message lives for, unless the sender asked for less
+    pub message_ttl: Option<u64>,
+    /// Last message or join, for closing rooms that have gone quiet
+    pub last_activity: Instant,
+    /// Key fingerprints of banned members, kept for the life of the room
+    pub banned: HashSet<Vec<u8>>,
+    /// Hold joins until the host approves them
+    pub require_approval: bool,
+    /// Largest file members may share, 0 turns file sharing off
+    pub max_file_size: u64,
+    /// Files offered in the room, so chunks can be checked against who offered them
+    pub file_routes: HashMap<String, FileRoute>,
+    /// The host's own offers and downloads
+    pub transfers: Transfers,
+    /// Files being passed from one member to another, by file id and recipient
+    pub file_relays: HashMap<(String, String), mpsc::UnboundedSender<ServerData>>,
+    /// Signing key of whoever sent each message still around, so only they can edit or delete
+    /// it. Usernames can be taken again once someone leaves, their key can't.
+    pub message_senders: HashMap<String, Vec<u8>>,
+    pub host_presence: PresenceState,
+    pub slow_consumer: SlowConsumer,
+}
+
+pub struct FileRoute {
+    pub sender: String,
+    pub size: u64,
+}
+
+impl Default for ChatData {
+    fn default() -> Self {
+        ChatData {
+            key_cipher: Aes256SivAead::new(&Aes256SivAead::generate_key(&mut OsRng)),
+            key: Vec::new(),
+            epoch: 0,
+            peer_map: HashMap::new(),
+            user_limit: 2,
+            host_username: String::new(),
+            previous_cipher: None,
+            rotated_at: Instant::now(),
+            signing_key: SigningKey::generate(&mut OsRng),
+            direct_secret: StaticSecret::random_from_rng(OsRng),
+            history: None,
+            message_ttl: None,
+            last_activity: Instant::now(),
+            banned: HashSet::new(),
+            require_approval: false,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            file_routes: HashMap::new(),
+            transfers: Transfers::default(),
+            file_relays: HashMap::new(),
+            message_senders: HashMap::new(),
+            host_presence: PresenceState::Online,
+            slow_consumer: SlowConsumer::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::outbox::test_socket;
+
+    /// A client whose connection is down, so frames are only kept for replaying
+    async fn dropped_client() -> Client {
+        let (write, _) = test_socket().await;
+        let mut client = Client::new(write, SlowConsumer::Disconnect);
+        client.registered = true;
+        client.disconnected_at = Some(Instant::now());
+        client
+    }
+
+    #[tokio::test]
+    async fn sent_since_replays_after_received() {
+        let mut client = dropped_client().await;
+        assert_eq!(client.sent_since(0), Some((Vec::new(), 0)));
+        for frame in ["one", "two", "three"] {
+            client.send(frame.into()).unwrap();
+        }
+        assert_eq!(
+            client.sent_since(1),
+            Some((vec!["two".to_string(), "three".to_string()], 0))
+        );
+        assert_eq!(client.sent_since(3), Some((Vec::new(), 0)));
+        assert_eq!(client.sent_since(4), None);
+    }
+
+    #[tokio::test]
+    async fn sent_since_gives_up_on_frames_no_longer_kept() {
+        let mut client = dropped_client().await;
+        let frame = "x".repeat(RESUME_BUFFER_BYTES / 2);
+        for _ in 0..3 {
+            client.send(frame.clone()).unwrap();
+        }
+        assert_eq!(client.sent_since(0), None);
+        assert_eq!(
+            client.sent_since(1).map(|(frames, _)| frames.len()),
+            Some(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn sent_since_skips_expired_frames() {
+        let mut client = dropped_client().await;
+        client
+            .send_expiring("gone".into(), Some(Instant::now()))
+            .unwrap();
+        let later = Instant::now() + Duration::from_secs(60);
+        client.send_lossy("kept".into(), Some(later)).unwrap();
+        client.send("control".into()).unwrap();
+        assert_eq!(
+            client.sent_since(0),
+            Some((vec!["kept".to_string(), "control".to_string()], 1))
+        );
+    }
+}
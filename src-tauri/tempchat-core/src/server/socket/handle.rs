@@ -0,0 +1,1707 @@
+use crate::events::{ChatEvent, EventSink};
+use crate::files;
+use crate::protocol::{self, ClientData, ServerData};
+use crate::server::outbox::{Closed, QueueStats};
+use crate::server::proto::{
+    ChatData, Client, Exit, FileRoute, Room, StoredMessage, HISTORY_LIMIT, PREVIOUS_KEY_GRACE,
+    RESUME_GRACE,
+};
+use crate::structs::{
+    BroadcastMessage, DirectChat, DirectMessage, EncData, Error, FileAccept, FileChunk,
+    FileComplete, FileOffer, FileProgress, Handshake, Join, JoinMessage, JoinRequest, KeyMessage,
+    Kick, Member, MessageAction, MessageUpdate, Presence, PresenceState, Resume, Resumed,
+    ShutdownWarning, UserMessage, MAX_MESSAGE_TTL,
+};
+use crate::utils;
+use aes_siv::aead::{KeyInit, OsRng};
+use aes_siv::Aes256SivAead;
+use chrono::Local;
+use futures_util::stream::SplitStream;
+use futures_util::{lock::Mutex, stream::StreamExt};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::borrow::BorrowMut;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use uuid::Uuid;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+pub static ROOMS: Lazy<Mutex<HashMap<String, Arc<Room>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn register_room(
+    room_id: String,
+    chat_data: ChatData,
+    shutdown: broadcast::Sender<()>,
+    events: Arc<dyn EventSink>,
+) -> Arc<Room> {
+    let room = Arc::new(Room {
+        id: room_id.clone(),
+        chat_data: Mutex::new(chat_data),
+        shutdown,
+        events,
+    });
+    ROOMS.lock().await.insert(room_id, room.clone());
+    room
+}
+
+pub async fn get_room(room_id: &str) -> Option<Arc<Room>> {
+    ROOMS.lock().await.get(room_id).cloned()
+}
+
+pub async fn handle_connection(
+    room: &Room,
+    stream: TcpStream,
+) -> Result<Option<(SplitStream<WebSocketStream<TcpStream>>, String)>, serde_json::Error> {
+    if let Ok(ws_stream) = tokio_tungstenite::accept_async(stream).await {
+        let (write, read) = ws_stream.split();
+        let uid = Uuid::new_v4().to_string();
+
+        let mut chat_data = room.chat_data.lock().await;
+        let client = Client::new(write, chat_data.slow_consumer);
+        chat_data.peer_map.insert(uid.clone(), client);
+        return Ok(Some((read, uid)));
+    }
+    Ok(None)
+}
+
+pub async fn handle_message(room: &Room, message: &ClientData, uid: &str) -> Result<(), String> {
+    match message {
+        ClientData::EncData(enc_data) => {
+            if !registered(room, uid).await {
+                if let Err(_err) = send_err(room, uid, "User must be registered".into()).await {
+                    close_client(room, uid).await;
+                }
+                return Ok(());
+            }
+
+            let chat_data = room.chat_data.lock().await;
+            let decrypt_res = open_group_frame(&chat_data, enc_data).await;
+            drop(chat_data);
+
+            match decrypt_res {
+                Ok(Some(msg_data)) => {
+                    if let Ok(message_data) =
+                        serde_json::from_str::<UserMessage>(&String::from_utf8(msg_data).unwrap())
+                    {
+                        if message_data.content.len() > 5000 {
+                            let _ = send_err(room, uid, "Message too long".into()).await;
+                            return Ok(());
+                        }
+                        let signing_key = get_signing_key(room, uid).await;
+                        if !utils::verify_message(
+                            &message_data.content,
+                            &message_data.signature,
+                            &signing_key,
+                        ) {
+                            let _ = send_err(room, uid, "Invalid message signature".into()).await;
+                            return Ok(());
+                        }
+                        if let Err(err) = handle_user_message(room, &message_data, Some(uid)).await
+                        {
+                            println!("Error handling user message: {:?}", err);
+                            if let Err(_send_err) =
+                                send_err(room, uid, "Max joins reached".into()).await
+                            {
+                                close_client(room, uid).await;
+                            }
+                            return Err(err);
+                        }
+                    }
+                }
+                Ok(None) => {
+                    let _ = send_err(room, uid, STALE_KEY.into()).await;
+                }
+                Err(err) => {
+                    let _ = send_err(room, uid, err.clone()).await;
+                    return Err(err);
+                }
+            }
+        }
+        ClientData::Handshake(handshake) => {
+            if let Err(err) = handle_handshake(room, handshake, uid).await {
+                println!("Error handling handshake: {:?}", err);
+                close_client(room, uid).await;
+                return Err(err);
+            }
+        }
+        ClientData::Join(join_data) => {
+            if !protocol::supported_version(join_data.protocol_version) {
+                let err = format!(
+                    "Incompatible protocol version: the host speaks v{}, this client speaks v{}",
+                    protocol::PROTOCOL_VERSION,
+                    join_data.protocol_version
+                );
+                let _ = send_err(room, uid, err.clone()).await;
+                close_client(room, uid).await;
+                return Err(err);
+            }
+            if let Err(err) = handle_join(room, join_data, uid).await {
+                println!("Error handling join: {:?}", err);
+                let _ = send_err(room, uid, err.clone()).await;
+                close_client(room, uid).await;
+                return Err(err.to_string());
+            }
+        }
+        ClientData::FileOffer(_)
+        | ClientData::FileAccept(_)
+        | ClientData::FileChunk(_)
+        | ClientData::FileComplete(_) => {
+            if !registered(room, uid).await {
+                if let Err(_err) = send_err(room, uid, "User must be registered".into()).await {
+                    close_client(room, uid).await;
+                }
+                return Ok(());
+            }
+            if let Err(err) = handle_file_message(room, message, uid).await {
+                println!("Error handling file transfer: {:?}", err);
+                let _ = send_err(room, uid, err).await;
+            }
+        }
+        ClientData::Presence(enc_data) => {
+            if !registered(room, uid).await {
+                return Ok(());
+            }
+            let chat_data = room.chat_data.lock().await;
+            // A presence update that's too old to open isn't worth asking for again
+            let data = match open_group_frame(&chat_data, enc_data).await {
+                Ok(Some(data)) => data,
+                Ok(None) | Err(_) => return Ok(()),
+            };
+            drop(chat_data);
+            let state = serde_json::from_slice::<PresenceState>(&data)
+                .map_err(|_| "Invalid presence".to_string())?;
+            if state == PresenceState::Offline {
+                return Err("Invalid presence".into());
+            }
+            handle_presence(room, uid, state).await?;
+        }
+        ClientData::MessageAction(enc_data) => {
+            if !registered(room, uid).await {
+                if let Err(_err) = send_err(room, uid, "User must be registered".into()).await {
+                    close_client(room, uid).await;
+                }
+                return Ok(());
+            }
+            let chat_data = room.chat_data.lock().await;
+            let decrypt_res = open_group_frame(&chat_data, enc_data).await;
+            drop(chat_data);
+            let action = match decrypt_res {
+                Ok(Some(data)) => serde_json::from_slice::<MessageAction>(&data)
+                    .map_err(|_| "Invalid message action".to_string())?,
+                Ok(None) => {
+                    let _ = send_err(room, uid, STALE_KEY.into()).await;
+                    return Ok(());
+                }
+                Err(err) => {
+                    let _ = send_err(room, uid, err.clone()).await;
+                    return Err(err);
+                }
+            };
+            if let Err(err) = handle_message_action(room, &action, Some(uid)).await {
+                println!("Error handling message action: {:?}", err);
+                let _ = send_err(room, uid, err).await;
+            }
+        }
+        ClientData::Direct(direct) => {
+            if !registered(room, uid).await {
+                if let Err(_err) = send_err(room, uid, "User must be registered".into()).await {
+                    close_client(room, uid).await;
+                }
+                return Ok(());
+            }
+            if let Err(err) = route_direct(room, direct, uid).await {
+                println!("Error routing direct message: {:?}", err);
+                let _ = send_err(room, uid, err).await;
+            }
+        }
+        ClientData::Resume(resume) => {
+            if let Err(err) = handle_resume(room, resume, uid).await {
+                println!("Error resuming session: {:?}", err);
+            }
+        }
+        ClientData::Exit => client_left(room, uid).await,
+    };
+    Ok(())
+}
+
+/// Removes a client that said goodbye or whose connection is gone, and tells everyone else if
+/// they had joined
+pub async fn client_left(room: &Room, uid: &str) {
+    let mut chat_data = room.chat_data.lock().await;
+    let client = match chat_data.peer_map.remove(uid) {
+        Some(client) => client,
+        None => return,
+    };
+    client.outbox.close();
+    if !client.registered {
+        return;
+    }
+    rotate_key(&mut chat_data).await;
+    drop(chat_data);
+
+    room.events.send(
+        &room.id,
+        ChatEvent::Exit(Exit {
+            username: client.username.clone(),
+        }),
+    );
+    if let Err(err) = broadcast_presence(room, client.username, PresenceState::Offline).await {
+        println!("Couldn't announce departure: {:?}", err);
+    }
+}
+
+/// Called when a connection ends without a goodbye. Members keep their slot for
+/// `RESUME_GRACE` so they can pick up where they left off, anyone who hadn't joined yet is
+/// just removed.
+pub async fn connection_lost(room: &Room, uid: &str) {
+    let mut chat_data = room.chat_data.lock().await;
+    let client = match chat_data.peer_map.get_mut(uid) {
+        Some(client) => client,
+        None => return,
+    };
+    if !client.registered {
+        chat_data.peer_map.remove(uid).unwrap().outbox.close();
+        return;
+    }
+    if client.disconnected_at.is_none() {
+        client.disconnected_at = Some(Instant::now());
+        client.outbox.close();
+        println!("Holding {}'s slot for them to resume", client.username);
+    }
+}
+
+/// Moves a dropped member's slot over to their new connection and replays what they missed.
+/// The token is single use, a fresh one goes out with `Resumed`.
+async fn handle_resume(room: &Room, resume: &Resume, uid: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let candidate = chat_data
+        .peer_map
+        .iter()
+        .find(|(other_uid, client)| {
+            other_uid.as_str() != uid && client.registered && client.username == resume.username
+        })
+        .map(|(other_uid, _)| other_uid.clone());
+    // Only the slot's session key opens the token, so having seen it isn't enough
+    let token = match candidate
+        .as_ref()
+        .and_then(|slot_uid| chat_data.peer_map[slot_uid].session_cipher.as_ref())
+    {
+        Some(cipher) => utils::decrypt_message(&resume.token, cipher).await.ok(),
+        None => None,
+    };
+    let current_free = chat_data
+        .peer_map
+        .get(uid)
+        .is_some_and(|client| !client.registered);
+    let slot = candidate.filter(|slot_uid| {
+        token.as_ref().is_some_and(|token| {
+            Sha256::digest(token).to_vec() == chat_data.peer_map[slot_uid].resume_token
+        }) && current_free
+    });
+
+    let missed = slot.as_ref().map(|slot_uid| {
+        let client = chat_data.peer_map.get_mut(slot_uid).unwrap();
+        client.resume_token.clear();
+        if client.disconnected_at.is_none() {
+            // They're back before we noticed the old connection go
+            client.disconnected_at = Some(Instant::now());
+            client.outbox.close();
+        }
+        client.sent_since(resume.received)
+    });
+    let (slot_uid, (missed, skipped)) = match (slot, missed) {
+        (Some(slot_uid), Some(Some(missed))) => (slot_uid, missed),
+        (slot, _) => {
+            if let Some(connection) = chat_data.peer_map.get_mut(uid) {
+                // Outside the slot's frame count, the member starts over with a join
+                let failed = serde_json::to_string(&ServerData::ResumeFailed).unwrap();
+                let _ = connection.outbox.push(Message::Text(failed), false);
+            }
+            drop(chat_data);
+            if let Some(slot_uid) = slot {
+                // It's them, but they missed more than was kept. The slot goes so the join
+                // they send next isn't turned away over their own name.
+                client_left(room, &slot_uid).await;
+            }
+            return Err("No slot to resume".into());
+        }
+    };
+
+    let connection = chat_data.peer_map.remove(uid).unwrap();
+    let mut client = chat_data.peer_map.remove(&slot_uid).unwrap();
+    client.outbox.close();
+    client.outbox = connection.outbox;
+    client.disconnected_at = None;
+    client.last_seen = Instant::now();
+
+    let token = rand::random::<[u8; 32]>();
+    client.resume_token = Sha256::digest(token).to_vec();
+    let enc_token = utils::encrypt_data(&token, client.session_cipher.as_ref().unwrap(), 0).await?;
+    let resumed = serde_json::to_string(&ServerData::Resumed(Resumed {
+        token: enc_token,
+        skipped,
+    }))
+    .unwrap();
+    println!(
+        "{} resumed their session, {} missed frames replayed",
+        client.username,
+        missed.len()
+    );
+    let send_res = client.outbox.catch_up(
+        std::iter::once(resumed)
+            .chain(missed)
+            .map(Message::Text)
+            .collect(),
+    );
+    chat_data.peer_map.insert(uid.to_string(), client);
+    send_res.map_err(|err| err.to_string())
+}
+
+/// Notes that a client is still there. Any frame counts, pongs included.
+pub async fn touch_client(room: &Room, uid: &str) {
+    if let Some(client) = room.chat_data.lock().await.peer_map.get_mut(uid) {
+        client.last_seen = Instant::now();
+    }
+}
+
+/// Pings every client, treats the ones that haven't sent anything within `timeout` as
+/// dropped and lets go of slots nobody came back for within `RESUME_GRACE`
+pub async fn check_heartbeats(room: &Room, timeout: Duration) {
+    let mut chat_data = room.chat_data.lock().await;
+    let mut unresponsive = Vec::new();
+    let mut expired = Vec::new();
+    for (uid, client) in chat_data.peer_map.iter_mut() {
+        if let Some(disconnected_at) = client.disconnected_at {
+            if disconnected_at.elapsed() > RESUME_GRACE {
+                expired.push(uid.clone());
+            }
+        } else if client.last_seen.elapsed() > timeout {
+            unresponsive.push(uid.clone());
+        } else if let Err(err) = client.outbox.push(Message::Ping(Vec::new()), false) {
+            println!("Error pinging client: {:?}", err);
+        }
+    }
+    drop(chat_data);
+    for uid in unresponsive {
+        println!("Client {} stopped responding", uid);
+        connection_lost(room, &uid).await;
+    }
+    for uid in expired {
+        println!("Giving up on client {}", uid);
+        client_left(room, &uid).await;
+    }
+}
+
+/// Told to a member whose frame was under a key that's been replaced since
+const STALE_KEY: &str = "The chat key changed, send that again";
+
+/// Opens a frame a member sent under the group key. The key from before the last rotation
+/// still works for `PREVIOUS_KEY_GRACE`, a frame under anything older gives `None` and the
+/// member is asked to send it again.
+async fn open_group_frame(
+    chat_data: &ChatData,
+    enc_data: &EncData,
+) -> Result<Option<Vec<u8>>, String> {
+    let cipher = if enc_data.epoch == chat_data.epoch {
+        &chat_data.key_cipher
+    } else {
+        match chat_data.previous_cipher.as_ref() {
+            Some(cipher)
+                if chat_data.epoch.checked_sub(1) == Some(enc_data.epoch)
+                    && chat_data.rotated_at.elapsed() < PREVIOUS_KEY_GRACE =>
+            {
+                cipher
+            }
+            _ => return Ok(None),
+        }
+    };
+    utils::decrypt_message(enc_data, cipher).await.map(Some)
+}
+
+/// Replaces the group key and hands it to every registered member over their session
+/// cipher, so whoever just joined or left can't read traffic from the other side of the change.
+async fn rotate_key(chat_data: &mut ChatData) {
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let previous = std::mem::replace(&mut chat_data.key_cipher, Aes256SivAead::new(&key));
+    chat_data.previous_cipher = Some(previous);
+    chat_data.rotated_at = Instant::now();
+    chat_data.key = key.to_vec();
+    chat_data.epoch += 1;
+
+    let epoch = chat_data.epoch;
+    let chat_key = chat_data.key.clone();
+    for client in chat_data.peer_map.values_mut() {
+        if !client.registered {
+            continue;
+        }
+        let enc_key = match client.session_cipher.as_ref() {
+            Some(cipher) => utils::encrypt_data(&chat_key, cipher, 0).await,
+            None => continue,
+        };
+        match enc_key {
+            Ok(key) => {
+                let rotation = serde_json::to_string(&ServerData::KeyRotation(KeyMessage {
+                    epoch,
+                    key,
+                    resume_token: None,
+                }))
+                .unwrap();
+                if let Err(err) = client.send(rotation) {
+                    println!("Error sending key rotation to client: {:?}", err);
+                }
+            }
+            Err(err) => println!("Error encrypting rotated key: {:?}", err),
+        }
+    }
+}
+
+async fn registered(room: &Room, uid: &str) -> bool {
+    let mut chat_data = room.chat_data.lock().await;
+    let clients = chat_data.peer_map.borrow_mut();
+    clients.get(uid).is_some_and(|client| client.registered)
+}
+
+async fn get_username(room: &Room, uid: &str) -> String {
+    let mut chat_data = room.chat_data.lock().await;
+    let clients = chat_data.peer_map.borrow_mut();
+    clients
+        .get(uid)
+        .map_or_else(|| "".to_string(), |client| client.username.clone())
+}
+
+async fn get_signing_key(room: &Room, uid: &str) -> Vec<u8> {
+    let mut chat_data = room.chat_data.lock().await;
+    let clients = chat_data.peer_map.borrow_mut();
+    clients
+        .get(uid)
+        .and_then(|client| client.signing_key)
+        .map_or_else(Vec::new, |key| key.to_bytes().to_vec())
+}
+
+pub async fn handle_user_message(
+    room: &Room,
+    message: &UserMessage,
+    uid: Option<&str>,
+) -> Result<(), String> {
+    let (sender, signature, signing_key) = if let Some(uid) = uid {
+        (
+            get_username(room, uid).await,
+            message.signature.clone(),
+            get_signing_key(room, uid).await,
+        )
+    } else {
+        let chat_data = room.chat_data.lock().await;
+        (
+            chat_data.host_username.clone(),
+            utils::sign_message(&message.content, &chat_data.signing_key),
+            chat_data.signing_key.verifying_key().to_bytes().to_vec(),
+        )
+    };
+    let mut chat_data = room.chat_data.lock().await;
+    chat_data.last_activity = Instant::now();
+    let chat_ttl = chat_data.message_ttl;
+    drop(chat_data);
+    let ttl = clamp_ttl(message.ttl, chat_ttl);
+    let expires = expiry(ttl);
+    let mut send_data = BroadcastMessage {
+        id: Uuid::new_v4().to_string(),
+        sender,
+        content: message.content.clone(),
+        created: Local::now().format("%H:%M:%S").to_string(),
+        signature,
+        signing_key,
+        verified: false,
+        ttl,
+        edited: false,
+        reactions: BTreeMap::new(),
+    };
+    let string_data =
+        serde_json::to_string(&send_data).expect("Couldn't convert message to string");
+    // Client signatures were checked in handle_message and the host signed its own
+    send_data.verified = true;
+
+    let mut chat_data = room.chat_data.lock().await;
+    chat_data
+        .message_senders
+        .insert(send_data.id.clone(), send_data.signing_key.clone());
+    // Sending a message ends typing, members clear the indicator when the message arrives
+    let sender_presence = match uid {
+        Some(uid) => chat_data
+            .peer_map
+            .get_mut(uid)
+            .map(|client| &mut client.presence),
+        None => Some(&mut chat_data.host_presence),
+    };
+    if let Some(presence) = sender_presence.filter(|state| **state == PresenceState::Typing) {
+        *presence = PresenceState::Online;
+    }
+    let encrypted =
+        utils::encrypt_message(string_data.clone(), &chat_data.key_cipher, chat_data.epoch).await?;
+    let enc_data = serde_json::to_string(&ServerData::EncData(encrypted))
+        .expect("Couldn't convert encrypted message to string");
+    if let Some(history) = chat_data.history.as_mut() {
+        let stored = StoredMessage {
+            id: send_data.id.clone(),
+            expires,
+            data: utils::encrypt_message(string_data, &history.cipher, 0).await?,
+        };
+        if history.messages.len() >= HISTORY_LIMIT {
+            history.messages.pop_front();
+        }
+        history.messages.push_back(stored);
+    }
+
+    drop(chat_data);
+    broadcast_lossy(room, &enc_data, expires).await;
+    if let Some(ttl) = ttl {
+        expire_after(room.id.clone(), send_data.id.clone(), ttl);
+    }
+    room.events.send(&room.id, ChatEvent::Message(send_data));
+    Ok(())
+}
+
+/// Passes a sealed direct message on to its recipient, filling in who it's from. Only
+/// messages addressed to the host are opened here.
+async fn route_direct(room: &Room, direct: &DirectMessage, uid: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    chat_data.last_activity = Instant::now();
+    let (sender, sender_key) = chat_data
+        .peer_map
+        .get(uid)
+        .map(|client| {
+            (
+                client.username.clone(),
+                client.signing_key.map(|key| key.to_bytes().to_vec()),
+            )
+        })
+        .ok_or_else(|| "Client connection already closed".to_string())?;
+
+    if direct.username == chat_data.host_username {
+        let opened = utils::open_direct(
+            &direct.ephemeral_key,
+            &direct.data,
+            &chat_data.direct_secret,
+        )
+        .await?;
+        let host_key = chat_data.signing_key.verifying_key().to_bytes();
+        let chat_ttl = chat_data.message_ttl;
+        drop(chat_data);
+
+        let mut message = serde_json::from_slice::<BroadcastMessage>(&opened)
+            .map_err(|_| "Invalid direct message".to_string())?;
+        message.ttl = clamp_ttl(message.ttl, chat_ttl);
+        message.verified = message.sender == sender
+            && sender_key.as_ref() == Some(&message.signing_key)
+            && utils::verify_direct(
+                &message.content,
+                &host_key,
+                &message.signature,
+                &message.signing_key,
+            );
+        if let Some(ttl) = message.ttl {
+            expire_after(room.id.clone(), message.id.clone(), ttl);
+        }
+        room.events.send(
+            &room.id,
+            ChatEvent::Direct(DirectChat {
+                peer: sender,
+                message,
+            }),
+        );
+        return Ok(());
+    }
+
+    // Members seal their direct messages with the chat's TTL
+    let expires = expiry(clamp_ttl(None, chat_data.message_ttl));
+    let routed = serde_json::to_string(&ServerData::Direct(DirectMessage {
+        username: sender,
+        ephemeral_key: direct.ephemeral_key.clone(),
+        data: direct.data.clone(),
+    }))
+    .unwrap();
+    let recipient = chat_data
+        .peer_map
+        .values_mut()
+        .find(|client| client.registered && client.username == direct.username)
+        .ok_or_else(|| format!("{} isn't in this chat", direct.username))?;
+    recipient
+        .send_expiring(routed, expires)
+        .map_err(|err| err.to_string())
+}
+
+/// Sends a direct message from the host, sealed to the member's session key
+pub async fn send_direct(room: &Room, username: &str, content: String) -> Result<(), String> {
+    if content.len() > 5000 {
+        return Err("Message too long".into());
+    }
+    let mut chat_data = room.chat_data.lock().await;
+    chat_data.last_activity = Instant::now();
+    let (recipient_key, direct_key) = chat_data
+        .peer_map
+        .values()
+        .find(|client| client.registered && client.username == username)
+        .and_then(|client| Some((client.signing_key?.to_bytes(), client.direct_key.clone())))
+        .ok_or_else(|| format!("{} isn't in this chat", username))?;
+    let mut message = BroadcastMessage {
+        id: Uuid::new_v4().to_string(),
+        sender: chat_data.host_username.clone(),
+        signature: utils::sign_direct(&content, &recipient_key, &chat_data.signing_key),
+        content,
+        created: Local::now().format("%H:%M:%S").to_string(),
+        signing_key: chat_data.signing_key.verifying_key().to_bytes().to_vec(),
+        verified: false,
+        ttl: clamp_ttl(None, chat_data.message_ttl),
+        edited: false,
+        reactions: BTreeMap::new(),
+    };
+    let (ephemeral_key, data) =
+        utils::seal_direct(&serde_json::to_vec(&message).unwrap(), &direct_key).await?;
+    let direct_msg = serde_json::to_string(&ServerData::Direct(DirectMessage {
+        username: message.sender.clone(),
+        ephemeral_key,
+        data,
+    }))
+    .unwrap();
+    let recipient = chat_data
+        .peer_map
+        .values_mut()
+        .find(|client| client.registered && client.username == username)
+        .unwrap();
+    recipient
+        .send_expiring(direct_msg, expiry(message.ttl))
+        .map_err(|err| err.to_string())?;
+    drop(chat_data);
+
+    message.verified = true;
+    if let Some(ttl) = message.ttl {
+        expire_after(room.id.clone(), message.id.clone(), ttl);
+    }
+    room.events.send(
+        &room.id,
+        ChatEvent::Direct(DirectChat {
+            peer: username.to_string(),
+            message,
+        }),
+    );
+    Ok(())
+}
+
+/// Checks file transfer frames and passes them on. The host can read offers like any other
+/// member, but chunks stay under the file key unless they're meant for the host.
+async fn handle_file_message(room: &Room, message: &ClientData, uid: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let sender = chat_data
+        .peer_map
+        .get(uid)
+        .map(|client| client.username.clone())
+        .ok_or_else(|| "Client connection already closed".to_string())?;
+    match message {
+        ClientData::FileOffer(enc_data) => {
+            let data = open_group_frame(&chat_data, enc_data)
+                .await?
+                .ok_or_else(|| STALE_KEY.to_string())?;
+            let mut offer = serde_json::from_slice::<FileOffer>(&data)
+                .map_err(|_| "Invalid file offer".to_string())?;
+            check_file_size(&chat_data, offer.info.size)?;
+            if offer.info.name.is_empty() || offer.info.name.len() > 255 {
+                return Err("Invalid file name".into());
+            }
+            if chat_data.file_routes.contains_key(&offer.info.id) {
+                return Err("File was already offered".into());
+            }
+            offer.info.sender = sender.clone();
+            chat_data.last_activity = Instant::now();
+            chat_data.file_routes.insert(
+                offer.info.id.clone(),
+                FileRoute {
+                    sender,
+                    size: offer.info.size,
+                },
+            );
+
+            let encrypted = utils::encrypt_data(
+                &serde_json::to_vec(&offer).unwrap(),
+                &chat_data.key_cipher,
+                chat_data.epoch,
+            )
+            .await?;
+            let offer_msg = serde_json::to_string(&ServerData::FileOffer(encrypted)).unwrap();
+            let info = offer.info.clone();
+            chat_data.transfers.offered(offer);
+            drop(chat_data);
+
+            broadcast(room, &offer_msg).await;
+            room.events.send(&room.id, ChatEvent::FileOffer(info));
+        }
+        ClientData::FileAccept(accept) => {
+            let offered_by = chat_data
+                .file_routes
+                .get(&accept.id)
+                .map(|route| route.sender.clone())
+                .ok_or_else(|| "No such file offer".to_string())?;
+            if offered_by == chat_data.host_username {
+                drop(chat_data);
+                stream_file(room.id.clone(), accept.id.clone(), sender);
+            } else {
+                let accept_msg = ServerData::FileAccept(FileAccept {
+                    id: accept.id.clone(),
+                    username: sender,
+                });
+                send_to(&mut chat_data, &offered_by, &accept_msg)?;
+            }
+        }
+        ClientData::FileChunk(chunk) => {
+            let size = file_route_size(&chat_data, &chunk.id, &sender)?;
+            if chunk.index >= files::chunk_count(size)
+                || chunk.data.data.len() > files::MAX_CHUNK_LEN
+            {
+                return Err("Invalid file chunk".into());
+            }
+            let routed = FileChunk {
+                id: chunk.id.clone(),
+                username: sender,
+                index: chunk.index,
+                data: chunk.data.clone(),
+            };
+            if chunk.username == chat_data.host_username {
+                let progress = chat_data.transfers.receive_chunk(&routed).await?;
+                drop(chat_data);
+                room.events
+                    .send(&room.id, ChatEvent::FileProgress(progress));
+            } else {
+                relay_file_frame(
+                    room,
+                    &mut chat_data,
+                    &chunk.id,
+                    &chunk.username,
+                    ServerData::FileChunk(routed),
+                );
+            }
+        }
+        ClientData::FileComplete(complete) => {
+            file_route_size(&chat_data, &complete.id, &sender)?;
+            if complete.username == chat_data.host_username {
+                let file = chat_data.transfers.finish(&complete.id)?;
+                drop(chat_data);
+                room.events.send(&room.id, ChatEvent::FileReceived(file));
+            } else {
+                let complete_msg = ServerData::FileComplete(FileComplete {
+                    id: complete.id.clone(),
+                    username: sender,
+                });
+                relay_file_frame(
+                    room,
+                    &mut chat_data,
+                    &complete.id,
+                    &complete.username,
+                    complete_msg,
+                );
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn check_file_size(chat_data: &ChatData, size: u64) -> Result<(), String> {
+    if chat_data.max_file_size == 0 {
+        return Err("File sharing is turned off in this chat".into());
+    }
+    if size > chat_data.max_file_size {
+        return Err(format!(
+            "Files can be at most {} bytes in this chat",
+            chat_data.max_file_size
+        ));
+    }
+    Ok(())
+}
+
+/// Only whoever offered a file may send its chunks
+fn file_route_size(chat_data: &ChatData, id: &str, sender: &str) -> Result<u64, String> {
+    match chat_data.file_routes.get(id) {
+        Some(route) if route.sender == sender => Ok(route.size),
+        _ => Err("No such file offer".into()),
+    }
+}
+
+fn send_to(chat_data: &mut ChatData, username: &str, data: &ServerData) -> Result<(), String> {
+    let client = chat_data
+        .peer_map
+        .values_mut()
+        .find(|client| client.registered && client.username == username)
+        .ok_or_else(|| format!("{} isn't in this chat", username))?;
+    client
+        .send(serde_json::to_string(data).unwrap())
+        .map_err(|err| err.to_string())
+}
+
+/// Offers one of the host's files to the room
+pub async fn share_file(room: &Room, name: String, data: Vec<u8>) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    check_file_size(&chat_data, data.len() as u64)?;
+    chat_data.last_activity = Instant::now();
+    let host_username = chat_data.host_username.clone();
+    let offer = chat_data.transfers.share(host_username.clone(), name, data);
+    chat_data.file_routes.insert(
+        offer.info.id.clone(),
+        FileRoute {
+            sender: host_username,
+            size: offer.info.size,
+        },
+    );
+    let encrypted = utils::encrypt_data(
+        &serde_json::to_vec(&offer).unwrap(),
+        &chat_data.key_cipher,
+        chat_data.epoch,
+    )
+    .await?;
+    drop(chat_data);
+
+    let offer_msg = serde_json::to_string(&ServerData::FileOffer(encrypted)).unwrap();
+    broadcast(room, &offer_msg).await;
+    room.events.send(&room.id, ChatEvent::FileOffer(offer.info));
+    Ok(())
+}
+
+/// Asks the member who offered a file to send it to the host
+pub async fn accept_file(room: &Room, id: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let info = chat_data.transfers.accept(id)?;
+    let accept_msg = ServerData::FileAccept(FileAccept {
+        id: id.to_string(),
+        username: chat_data.host_username.clone(),
+    });
+    send_to(&mut chat_data, &info.sender, &accept_msg)
+}
+
+/// Sends one of the host's files to a member who accepted it
+fn stream_file(room_id: String, id: String, recipient: String) {
+    tokio::spawn(async move {
+        if let Err(err) = send_file_chunks(&room_id, &id, &recipient).await {
+            println!("Couldn't send file to {}: {:?}", recipient, err);
+        }
+    });
+}
+
+async fn send_file_chunks(room_id: &str, id: &str, recipient: &str) -> Result<(), String> {
+    let room = get_room(room_id)
+        .await
+        .ok_or_else(|| "Room already closed".to_string())?;
+    let (offer, data) = room
+        .chat_data
+        .lock()
+        .await
+        .transfers
+        .outgoing(id)
+        .ok_or_else(|| "No such file".to_string())?;
+    let sender = offer.info.sender.clone();
+    for index in 0..files::chunk_count(offer.info.size) {
+        let chunk = ServerData::FileChunk(FileChunk {
+            id: id.to_string(),
+            username: sender.clone(),
+            index,
+            data: files::encrypt_chunk(&offer, &data, index).await?,
+        });
+        wait_for_room(&room, recipient).await;
+        send_to(&mut *room.chat_data.lock().await, recipient, &chunk)?;
+        room.events.send(
+            &room.id,
+            ChatEvent::FileProgress(FileProgress {
+                id: id.to_string(),
+                peer: recipient.to_string(),
+                outgoing: true,
+                bytes: ((index as u64 + 1) * files::CHUNK_SIZE as u64).min(offer.info.size),
+                size: offer.info.size,
+            }),
+        );
+    }
+    let complete = ServerData::FileComplete(FileComplete {
+        id: id.to_string(),
+        username: sender,
+    });
+    let mut chat_data = room.chat_data.lock().await;
+    send_to(&mut chat_data, recipient, &complete)
+}
+
+/// Hands a member's file frame to the task passing that file on to its recipient. Frames come
+/// out in order, and a recipient who can't keep up only holds up their own download instead of
+/// every frame the room is waiting to handle.
+fn relay_file_frame(
+    room: &Room,
+    chat_data: &mut ChatData,
+    id: &str,
+    recipient: &str,
+    frame: ServerData,
+) {
+    let key = (id.to_string(), recipient.to_string());
+    let last = matches!(frame, ServerData::FileComplete(_));
+    let relay = chat_data.file_relays.entry(key.clone()).or_insert_with(|| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(relay_file(room.id.clone(), key.clone(), rx));
+        tx
+    });
+    if relay.send(frame).is_err() {
+        println!("File relay to {} already stopped", recipient);
+    }
+    if last {
+        // The task finishes once it has sent everything queued
+        chat_data.file_relays.remove(&key);
+    }
+}
+
+async fn relay_file(
+    room_id: String,
+    key: (String, String),
+    mut frames: mpsc::UnboundedReceiver<ServerData>,
+) {
+    let room = match get_room(&room_id).await {
+        Some(room) => room,
+        None => return,
+    };
+    let recipient = &key.1;
+    while let Some(frame) = frames.recv().await {
+        wait_for_room(&room, recipient).await;
+        if let Err(err) = send_to(&mut *room.chat_data.lock().await, recipient, &frame) {
+            println!("Couldn't pass file on to {}: {:?}", recipient, err);
+            room.chat_data.lock().await.file_relays.remove(&key);
+            return;
+        }
+    }
+}
+
+/// Waits until a member's connection has caught up enough to take more file chunks. Only
+/// called from tasks of their own, never while handling frames for the whole room.
+async fn wait_for_room(room: &Room, username: &str) {
+    let outbox = room
+        .chat_data
+        .lock()
+        .await
+        .peer_map
+        .values()
+        .find(|client| client.registered && client.username == username)
+        .map(|client| client.outbox.clone());
+    if let Some(outbox) = outbox {
+        outbox.ready().await;
+    }
+}
+
+/// Checks an edit, deletion or reaction and passes it on to everyone. Only whoever sent a
+/// message can edit or delete it. `uid` is `None` for the host's own actions.
+pub async fn handle_message_action(
+    room: &Room,
+    action: &MessageAction,
+    uid: Option<&str>,
+) -> Result<(), String> {
+    let (username, signing_key) = if let Some(uid) = uid {
+        (
+            get_username(room, uid).await,
+            get_signing_key(room, uid).await,
+        )
+    } else {
+        let chat_data = room.chat_data.lock().await;
+        (
+            chat_data.host_username.clone(),
+            chat_data.signing_key.verifying_key().to_bytes().to_vec(),
+        )
+    };
+    let mut chat_data = room.chat_data.lock().await;
+    let mut action = action.clone();
+    let owner = chat_data
+        .message_senders
+        .get(action.id())
+        .cloned()
+        .ok_or_else(|| "That message is gone".to_string())?;
+    match &mut action {
+        MessageAction::Edit {
+            id,
+            content,
+            signature,
+        } => {
+            if owner != signing_key {
+                return Err("You can only edit your own messages".into());
+            }
+            if content.len() > 5000 {
+                return Err("Message too long".into());
+            }
+            if uid.is_none() {
+                *signature = utils::sign_edit(id, content, &chat_data.signing_key);
+            } else if !utils::verify_edit(id, content, signature, &signing_key) {
+                return Err("Invalid message signature".into());
+            }
+        }
+        MessageAction::Delete { id } => {
+            if owner != signing_key {
+                return Err("You can only delete your own messages".into());
+            }
+            chat_data.message_senders.remove(id.as_str());
+        }
+        MessageAction::React { emoji, .. } | MessageAction::Unreact { emoji, .. } => {
+            if emoji.is_empty() || emoji.chars().count() > 8 {
+                return Err("Invalid reaction".into());
+            }
+        }
+    }
+    chat_data.last_activity = Instant::now();
+    let update = MessageUpdate {
+        username,
+        action,
+        verified: false,
+    };
+
+    if let Some(history) = chat_data.history.as_mut() {
+        let id = update.action.id();
+        if let MessageAction::Delete { .. } = update.action {
+            history.messages.retain(|stored| stored.id != id);
+        } else if let Some(stored) = history.messages.iter_mut().find(|stored| stored.id == id) {
+            let data = utils::decrypt_message(&stored.data, &history.cipher).await?;
+            let mut message = serde_json::from_slice::<BroadcastMessage>(&data)
+                .map_err(|_| "Invalid stored message".to_string())?;
+            apply_update(&mut message, &update);
+            stored.data =
+                utils::encrypt_data(&serde_json::to_vec(&message).unwrap(), &history.cipher, 0)
+                    .await?;
+        }
+    }
+    let encrypted = utils::encrypt_data(
+        &serde_json::to_vec(&update).unwrap(),
+        &chat_data.key_cipher,
+        chat_data.epoch,
+    )
+    .await?;
+    drop(chat_data);
+    let update_msg = serde_json::to_string(&ServerData::MessageUpdate(encrypted)).unwrap();
+
+    broadcast(room, &update_msg).await;
+    let mut update = update;
+    update.verified = true;
+    room.events.send(&room.id, ChatEvent::MessageUpdate(update));
+    Ok(())
+}
+
+/// Applies an update to a stored copy of the message it's for
+fn apply_update(message: &mut BroadcastMessage, update: &MessageUpdate) {
+    match &update.action {
+        MessageAction::Edit {
+            content, signature, ..
+        } => {
+            message.content = content.clone();
+            message.signature = signature.clone();
+            message.edited = true;
+        }
+        MessageAction::Delete { .. } => {}
+        MessageAction::React { emoji, .. } => {
+            let reacted = message.reactions.entry(emoji.clone()).or_default();
+            if !reacted.contains(&update.username) {
+                reacted.push(update.username.clone());
+            }
+        }
+        MessageAction::Unreact { emoji, .. } => {
+            if let Some(reacted) = message.reactions.get_mut(emoji) {
+                reacted.retain(|username| username != &update.username);
+                if reacted.is_empty() {
+                    message.reactions.remove(emoji);
+                }
+            }
+        }
+    }
+}
+
+/// TTL a message actually gets: what the sender asked for, never longer than the chat's own or
+/// `MAX_MESSAGE_TTL`
+fn clamp_ttl(requested: Option<u64>, chat_ttl: Option<u64>) -> Option<u64> {
+    match (requested.filter(|ttl| *ttl > 0), chat_ttl) {
+        (Some(ttl), Some(chat_ttl)) => Some(ttl.min(chat_ttl)),
+        (ttl, chat_ttl) => ttl.or(chat_ttl),
+    }
+    .map(|ttl| ttl.min(MAX_MESSAGE_TTL))
+}
+
+/// When a message with this TTL is gone, `None` for no TTL or one too long to count down
+fn expiry(ttl: Option<u64>) -> Option<Instant> {
+    ttl.and_then(|ttl| Instant::now().checked_add(Duration::from_secs(ttl)))
+}
+
+/// Members run their own timers off the TTL in the message, the host only has to drop its copy
+fn expire_after(room_id: String, message_id: String, ttl: u64) {
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(ttl)).await;
+        if let Some(room) = get_room(&room_id).await {
+            let mut chat_data = room.chat_data.lock().await;
+            chat_data.message_senders.remove(&message_id);
+            if let Some(history) = chat_data.history.as_mut() {
+                history.messages.retain(|stored| stored.id != message_id);
+            }
+            drop(chat_data);
+            room.events
+                .send(&room.id, ChatEvent::ExpireMessage(message_id));
+        }
+    });
+}
+
+async fn broadcast(room: &Room, message: &str) {
+    send_to_all(room, message, false, None).await
+}
+
+/// Broadcasts a chat message or presence update, which a member that can't keep up may miss.
+/// Nothing is replayed to a resuming member after `expires`.
+async fn broadcast_lossy(room: &Room, message: &str, expires: Option<Instant>) {
+    send_to_all(room, message, true, expires).await
+}
+
+async fn send_to_all(room: &Room, message: &str, lossy: bool, expires: Option<Instant>) {
+    let mut chat_data = room.chat_data.lock().await;
+    let clients = chat_data.peer_map.borrow_mut();
+    for client in clients.values_mut() {
+        if !client.registered {
+            continue;
+        }
+        let res = if lossy {
+            client.send_lossy(message.to_string(), expires)
+        } else {
+            client.send_expiring(message.to_string(), expires)
+        };
+        if let Err(err) = res {
+            println!("Error broadcasting message to client: {:?}", err);
+        }
+    }
+}
+
+async fn handle_handshake(room: &Room, handshake: &Handshake, uid: &str) -> Result<(), String> {
+    let client_pub = utils::parse_handshake_key(&handshake.pub_key)?;
+
+    let mut chat_data = room.chat_data.lock().await;
+    let client = chat_data
+        .peer_map
+        .get_mut(uid)
+        .ok_or_else(|| "Client connection already closed".to_string())?;
+    if client.session_cipher.is_some() {
+        return Err("Handshake already completed".into());
+    }
+
+    // A fresh secret per connection, dropped as soon as the session key is derived
+    let host_secret = EphemeralSecret::random_from_rng(OsRng);
+    let host_pub = PublicKey::from(&host_secret);
+    let shared = host_secret.diffie_hellman(&client_pub);
+    client.session_cipher = Some(utils::derive_session_cipher(
+        &shared,
+        &client_pub,
+        &host_pub,
+    )?);
+
+    let identity = &chat_data.signing_key;
+    let handshake_msg = serde_json::to_string(&ServerData::Handshake(Handshake {
+        pub_key: host_pub.as_bytes().to_vec(),
+        identity_key: identity.verifying_key().to_bytes().to_vec(),
+        signature: utils::sign_handshake(&client_pub, &host_pub, identity),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        max_file_size: chat_data.max_file_size,
+        message_ttl: chat_data.message_ttl,
+    }))
+    .unwrap();
+    let client = chat_data.peer_map.get_mut(uid).unwrap();
+    client.send(handshake_msg).map_err(|err| err.to_string())
+}
+
+async fn handle_join(room: &Room, join_data: &Join, uid: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    if chat_data
+        .banned
+        .contains(&utils::fingerprint(&join_data.signing_key))
+    {
+        return Err("You have been banned from this chat".into());
+    }
+    let limit = chat_data.user_limit;
+
+    let joined = chat_data
+        .peer_map
+        .values()
+        .filter(|client| client.registered)
+        .count();
+
+    if joined as i32 + 1 > limit {
+        return Err("Max joins for chat reached".into());
+    }
+
+    if join_data.username == chat_data.host_username {
+        return Err("Username already taken".into());
+    }
+
+    let clients = chat_data.peer_map.borrow_mut();
+
+    for client in clients.values() {
+        if client.username == join_data.username {
+            return Err("Username already taken".into());
+        }
+    }
+
+    let client_res = clients
+        .get(uid)
+        .ok_or(tokio_tungstenite::tungstenite::Error::AlreadyClosed);
+    if client_res.is_err() {
+        return Err("Client connection already closed".into());
+    }
+    let client = client_res.unwrap();
+    if client.username.len() > 15 {
+        return Err("Username too long".into());
+    }
+    if client.session_cipher.is_none() {
+        return Err("Handshake must be completed before joining".into());
+    }
+    let signing_key = utils::parse_signing_key(&join_data.signing_key)?;
+    utils::parse_handshake_key(&join_data.direct_key)?;
+    if !utils::verify_direct_key(
+        &join_data.direct_key,
+        &join_data.direct_key_signature,
+        &join_data.signing_key,
+    ) {
+        return Err("Invalid direct message key signature".into());
+    }
+
+    if chat_data.require_approval {
+        let client = chat_data.peer_map.get_mut(uid).unwrap();
+        // Hold the name so nobody else can take it while they wait
+        client.username = join_data.username.clone();
+        client.pending_join = Some(join_data.clone());
+        let pending_msg = serde_json::to_string(&ServerData::JoinPending).unwrap();
+        let _ = client.send(pending_msg);
+        drop(chat_data);
+
+        room.events.send(
+            &room.id,
+            ChatEvent::JoinRequest(JoinRequest {
+                username: join_data.username.clone(),
+                fingerprint: utils::safety_number(signing_key.as_bytes()),
+            }),
+        );
+        return Ok(());
+    }
+    drop(chat_data);
+    admit(room, join_data, uid).await
+}
+
+/// Registers a member, hands them the group key and tells everyone they're in
+async fn admit(room: &Room, join_data: &Join, uid: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let signing_key = utils::parse_signing_key(&join_data.signing_key)?;
+    if !chat_data.peer_map.contains_key(uid) {
+        return Err("Client connection already closed".into());
+    }
+
+    // Rotate before registering so the new member never sees the previous key
+    rotate_key(&mut chat_data).await;
+    chat_data.last_activity = Instant::now();
+    let epoch = chat_data.epoch;
+    let chat_key = chat_data.key.clone();
+    let client = chat_data.peer_map.get_mut(uid).unwrap();
+    client.username = join_data.username.clone();
+    client.registered = true;
+    client.signing_key = Some(signing_key);
+    client.direct_key = join_data.direct_key.clone();
+    client.direct_key_signature = join_data.direct_key_signature.clone();
+    client.protocol_version = join_data.protocol_version;
+
+    let join_message = JoinMessage {
+        joined: client.username.clone(),
+        signing_key: join_data.signing_key.clone(),
+        direct_key: join_data.direct_key.clone(),
+        direct_key_signature: join_data.direct_key_signature.clone(),
+    };
+    let join_broadcast =
+        serde_json::to_string(&ServerData::JoinMessage(join_message.clone())).unwrap();
+
+    let enc_key =
+        utils::encrypt_data(&chat_key, client.session_cipher.as_ref().unwrap(), 0).await?;
+    let token = rand::random::<[u8; 32]>();
+    client.resume_token = Sha256::digest(token).to_vec();
+    let enc_token = utils::encrypt_data(&token, client.session_cipher.as_ref().unwrap(), 0).await?;
+
+    let key_msg = serde_json::to_string(&ServerData::KeyMessage(KeyMessage {
+        epoch,
+        key: enc_key,
+        resume_token: Some(enc_token),
+    }))
+    .unwrap();
+
+    let _ = client.send(key_msg);
+
+    let roster = member_list(&chat_data);
+    let client = chat_data.peer_map.get_mut(uid).unwrap();
+    let enc_roster = utils::encrypt_data(
+        &serde_json::to_vec(&roster).unwrap(),
+        client.session_cipher.as_ref().unwrap(),
+        0,
+    )
+    .await?;
+    let roster_msg = serde_json::to_string(&ServerData::Roster(enc_roster)).unwrap();
+    let _ = client.send(roster_msg);
+
+    drop(chat_data);
+    broadcast(room, &join_broadcast).await;
+    room.events.send(&room.id, ChatEvent::Join(join_message));
+    if let Err(err) = send_history(room, uid).await {
+        println!("Couldn't send history to client: {:?}", err);
+    }
+
+    Ok(())
+}
+
+/// Replays the stored messages to a member that just joined, re-encrypted under their session key
+async fn send_history(room: &Room, uid: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let chat_data = &mut *chat_data;
+    let history = match chat_data.history.as_ref() {
+        Some(history) if !history.messages.is_empty() => history,
+        _ => return Ok(()),
+    };
+    let client = chat_data
+        .peer_map
+        .get_mut(uid)
+        .ok_or_else(|| "Client connection already closed".to_string())?;
+    let session_cipher = client
+        .session_cipher
+        .as_ref()
+        .ok_or_else(|| "Handshake not completed".to_string())?;
+
+    let mut messages = Vec::with_capacity(history.messages.len());
+    for stored in history.messages.iter() {
+        let mut message = utils::decrypt_message(&stored.data, &history.cipher).await?;
+        if let Some(expires) = stored.expires {
+            let remaining = expires.saturating_duration_since(Instant::now()).as_secs();
+            if remaining == 0 {
+                continue;
+            }
+            // The new member starts its timer on arrival, so only hand over what's left
+            let mut broadcast_data = serde_json::from_slice::<BroadcastMessage>(&message)
+                .map_err(|err| err.to_string())?;
+            broadcast_data.ttl = Some(remaining);
+            message = serde_json::to_vec(&broadcast_data).unwrap();
+        }
+        messages.push(utils::encrypt_data(&message, session_cipher, 0).await?);
+    }
+    let history_msg = serde_json::to_string(&ServerData::History(messages)).unwrap();
+    client.send(history_msg).map_err(|err| err.to_string())
+}
+
+async fn send_err(room: &Room, uid: &str, message: String) -> Result<(), Closed> {
+    let mut chat_data = room.chat_data.lock().await;
+    let clients = chat_data.peer_map.borrow_mut();
+    if let Some(client) = clients.get_mut(uid) {
+        let error = serde_json::to_string(&ServerData::Error(Error { error_msg: message }))
+            .expect("Couldn't convert error message");
+        client.send(error)
+    } else {
+        Err(Closed)
+    }
+}
+
+/// Drops a client that broke the protocol. Members are let go the same way as when they leave,
+/// so everyone still hears they're gone.
+pub async fn close_client(room: &Room, uid: &str) {
+    let mut chat_data = room.chat_data.lock().await;
+    if chat_data
+        .peer_map
+        .get(uid)
+        .is_some_and(|client| client.registered)
+    {
+        drop(chat_data);
+        client_left(room, uid).await;
+    } else if let Some(client) = chat_data.peer_map.remove(uid) {
+        client.outbox.close();
+    }
+}
+
+/// Lets in someone who's waiting for approval
+pub async fn approve_join(room: &Room, username: &str) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let joined = chat_data
+        .peer_map
+        .values()
+        .filter(|client| client.registered)
+        .count();
+    if joined as i32 + 1 > chat_data.user_limit {
+        return Err("Max joins for chat reached".into());
+    }
+    let (uid, join_data) = chat_data
+        .peer_map
+        .iter_mut()
+        .find(|(_, client)| {
+            client
+                .pending_join
+                .as_ref()
+                .is_some_and(|join| join.username == username)
+        })
+        .map(|(uid, client)| (uid.clone(), client.pending_join.take().unwrap()))
+        .ok_or_else(|| format!("{} isn't waiting to join", username))?;
+    drop(chat_data);
+
+    if let Err(err) = admit(room, &join_data, &uid).await {
+        let _ = send_err(room, &uid, err.clone()).await;
+        close_client(room, &uid).await;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Turns away someone who's waiting for approval
+pub async fn deny_join(room: &Room, username: &str) -> Result<(), String> {
+    let chat_data = room.chat_data.lock().await;
+    let uid = chat_data
+        .peer_map
+        .iter()
+        .find(|(_, client)| {
+            client
+                .pending_join
+                .as_ref()
+                .is_some_and(|join| join.username == username)
+        })
+        .map(|(uid, _)| uid.clone())
+        .ok_or_else(|| format!("{} isn't waiting to join", username))?;
+    drop(chat_data);
+
+    let _ = send_err(room, &uid, "The host didn't let you in".into()).await;
+    close_client(room, &uid).await;
+    Ok(())
+}
+
+/// Removes a member and tells them why. Banning also remembers their key fingerprint so they
+/// can't come back under another name.
+pub async fn kick_member(
+    room: &Room,
+    username: &str,
+    reason: String,
+    ban: bool,
+) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let uid = chat_data
+        .peer_map
+        .iter()
+        .find(|(_, client)| client.registered && client.username == username)
+        .map(|(uid, _)| uid.clone())
+        .ok_or_else(|| format!("{} isn't in this chat", username))?;
+    let mut client = chat_data.peer_map.remove(&uid).unwrap();
+    if ban {
+        if let Some(signing_key) = client.signing_key {
+            chat_data
+                .banned
+                .insert(utils::fingerprint(signing_key.as_bytes()));
+        }
+    }
+
+    let kick_msg = serde_json::to_string(&ServerData::Kick(Kick {
+        reason,
+        banned: ban,
+    }))
+    .unwrap();
+    let _ = client.send(kick_msg);
+    client.outbox.close();
+    rotate_key(&mut chat_data).await;
+    drop(chat_data);
+
+    room.events.send(
+        &room.id,
+        ChatEvent::Exit(Exit {
+            username: username.to_string(),
+        }),
+    );
+    broadcast_presence(room, username.to_string(), PresenceState::Offline).await
+}
+
+/// A member's presence goes out at most once inside this window, so one who keeps typing or
+/// flips between states doesn't flood the room. Only their latest state is sent when it's up.
+const PRESENCE_THROTTLE: Duration = Duration::from_secs(2);
+
+async fn handle_presence(room: &Room, uid: &str, state: PresenceState) -> Result<(), String> {
+    let mut chat_data = room.chat_data.lock().await;
+    let client = chat_data
+        .peer_map
+        .get_mut(uid)
+        .ok_or_else(|| "Client connection already closed".to_string())?;
+    let unchanged = client.presence == state;
+    client.presence = state;
+    if client.presence_pending {
+        return Ok(());
+    }
+    let wait = PRESENCE_THROTTLE.saturating_sub(client.presence_at.elapsed());
+    if wait.is_zero() {
+        client.presence_at = Instant::now();
+        let username = client.username.clone();
+        drop(chat_data);
+        return broadcast_presence(room, username, state).await;
+    }
+    if !unchanged {
+        client.presence_pending = true;
+        send_presence_after(room.id.clone(), client.username.clone(), wait);
+    }
+    Ok(())
+}
+
+/// Passes on whatever state a member ended up in once the throttle lets it through. Looked up
+/// by name since a resume moves the member to a new connection.
+fn send_presence_after(room_id: String, username: String, wait: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(wait).await;
+        let room = match get_room(&room_id).await {
+            Some(room) => room,
+            None => return,
+        };
+        let mut chat_data = room.chat_data.lock().await;
+        let client = chat_data
+            .peer_map
+            .values_mut()
+            .find(|client| client.registered && client.username == username);
+        let state = match client {
+            Some(client) => {
+                client.presence_pending = false;
+                client.presence_at = Instant::now();
+                client.presence
+            }
+            // They left, which went out as going offline
+            None => return,
+        };
+        drop(chat_data);
+        if let Err(err) = broadcast_presence(&room, username, state).await {
+            println!("Error sending presence: {:?}", err);
+        }
+    });
+}
+
+/// Tells every member whether the host is around or typing
+pub async fn send_host_presence(room: &Room, state: PresenceState) -> Result<(), String> {
+    if state == PresenceState::Offline {
+        return Err("Close the chat to go offline".into());
+    }
+    let mut chat_data = room.chat_data.lock().await;
+    chat_data.host_presence = state;
+    let username = chat_data.host_username.clone();
+    drop(chat_data);
+    broadcast_presence(room, username, state).await
+}
+
+async fn broadcast_presence(
+    room: &Room,
+    username: String,
+    state: PresenceState,
+) -> Result<(), String> {
+    let presence = Presence { username, state };
+    let chat_data = room.chat_data.lock().await;
+    let encrypted = utils::encrypt_data(
+        &serde_json::to_vec(&presence).unwrap(),
+        &chat_data.key_cipher,
+        chat_data.epoch,
+    )
+    .await?;
+    drop(chat_data);
+    let presence_msg = serde_json::to_string(&ServerData::Presence(encrypted)).unwrap();
+    // Offline is the only word members get that someone left, so it's never dropped
+    if state == PresenceState::Offline {
+        broadcast(room, &presence_msg).await;
+    } else {
+        broadcast_lossy(room, &presence_msg, None).await;
+    }
+    // Leaving is already reported as an exit
+    if state != PresenceState::Offline {
+        room.events.send(&room.id, ChatEvent::Presence(presence));
+    }
+    Ok(())
+}
+
+/// Everyone in the room and what they're up to, the host first
+pub async fn presence_list(room: &Room) -> Vec<Presence> {
+    member_list(&*room.chat_data.lock().await)
+        .into_iter()
+        .map(|member| Presence {
+            username: member.username,
+            state: member.state,
+        })
+        .collect()
+}
+
+/// Everyone in the room with their signing keys, the host first
+pub fn member_list(chat_data: &ChatData) -> Vec<Member> {
+    let host_key = chat_data.signing_key.verifying_key().to_bytes().to_vec();
+    let host_direct_key = PublicKey::from(&chat_data.direct_secret)
+        .as_bytes()
+        .to_vec();
+    let host_direct_key_signature =
+        utils::sign_direct_key(&host_direct_key, &chat_data.signing_key);
+    let mut members = vec![Member::new(
+        chat_data.host_username.clone(),
+        host_key,
+        host_direct_key,
+        host_direct_key_signature,
+        chat_data.host_presence,
+        true,
+    )];
+    members.extend(
+        chat_data
+            .peer_map
+            .values()
+            .filter(|client| client.registered)
+            .filter_map(|client| {
+                let signing_key = client.signing_key?.to_bytes().to_vec();
+                Some(Member::new(
+                    client.username.clone(),
+                    signing_key,
+                    client.direct_key.clone(),
+                    client.direct_key_signature.clone(),
+                    client.presence,
+                    false,
+                ))
+            }),
+    );
+    members
+}
+
+/// How much is waiting to go out to each member
+pub async fn queue_stats(room: &Room) -> Vec<QueueStats> {
+    room.chat_data
+        .lock()
+        .await
+        .peer_map
+        .values()
+        .filter(|client| client.registered)
+        .map(|client| {
+            client
+                .outbox
+                .stats(client.username.clone(), client.disconnected_at.is_none())
+        })
+        .collect()
+}
+
+/// Tells the host and every member how long the room has left
+pub async fn warn_shutdown(room: &Room, warning: ShutdownWarning) {
+    let warning_msg = serde_json::to_string(&ServerData::ShutdownWarning(warning.clone())).unwrap();
+    broadcast(room, &warning_msg).await;
+    room.events
+        .send(&room.id, ChatEvent::ShutdownWarning(warning));
+}
+
+pub async fn chat_shutdown(room: &Room) {
+    let mut chat_data = room.chat_data.lock().await;
+    let clients = chat_data.peer_map.borrow_mut();
+    for client in clients.values_mut() {
+        let _ = client.send(serde_json::to_string(&ServerData::Shutdown).unwrap());
+        client.outbox.close();
+    }
+    clients.clear();
+    chat_data.host_username.clear();
+    chat_data.key.clear();
+    chat_data.previous_cipher = None;
+    chat_data.epoch = 0;
+    chat_data.user_limit = 0;
+    drop(chat_data);
+    ROOMS.lock().await.remove(&room.id);
+    let _ = room.shutdown.send(());
+}
+
+pub async fn shutdown_all() {
+    let rooms: Vec<Arc<Room>> = ROOMS.lock().await.values().cloned().collect();
+    for room in rooms {
+        chat_shutdown(&room).await;
+    }
+}
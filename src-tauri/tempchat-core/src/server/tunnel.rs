@@ -0,0 +1,135 @@
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use localtunnel_client::{open_tunnel, ClientConfig};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{connect_async, tungstenite::Message::Text};
+
+use crate::structs::RelayControl;
+use crate::utils;
+
+pub struct TunnelRequest {
+    pub room_id: String,
+    pub local_host: String,
+    pub local_port: u16,
+    pub max_conn: u8,
+    pub shutdown: broadcast::Sender<()>,
+}
+
+/// Makes the room's local listener reachable by members and returns the URL they connect to.
+/// Providers should stop forwarding once `shutdown` fires.
+#[async_trait]
+pub trait TunnelProvider: Send + Sync {
+    async fn open(&self, request: TunnelRequest) -> Result<String, String>;
+}
+
+pub struct LocalTunnel {
+    pub server: String,
+}
+
+impl Default for LocalTunnel {
+    fn default() -> Self {
+        LocalTunnel {
+            server: "https://loca.lt".into(),
+        }
+    }
+}
+
+#[async_trait]
+impl TunnelProvider for LocalTunnel {
+    async fn open(&self, request: TunnelRequest) -> Result<String, String> {
+        let config = ClientConfig {
+            server: Some(self.server.clone()),
+            subdomain: Some(request.room_id),
+            local_host: Some(request.local_host),
+            local_port: request.local_port,
+            shutdown_signal: request.shutdown,
+            max_conn: request.max_conn,
+            credential: None,
+        };
+        open_tunnel(config)
+            .await
+            .map_err(|err| format!("Couldn't open tunnel: {}", err))
+    }
+}
+
+/// Registers the room with a self-hosted relay and bridges every member the relay announces
+/// to the local listener. The relay only ever sees the encrypted frames.
+pub struct RelayTunnel {
+    pub server: String,
+}
+
+#[async_trait]
+impl TunnelProvider for RelayTunnel {
+    async fn open(&self, request: TunnelRequest) -> Result<String, String> {
+        let relay_url = utils::to_ws_url(self.server.trim_end_matches('/'));
+        let (control, _) = connect_async(format!("{}/host/{}", relay_url, request.room_id))
+            .await
+            .map_err(|_| "Couldn't register room with relay".to_string())?;
+
+        let local_url = format!("ws://{}:{}", request.local_host, request.local_port);
+        let accept_url = format!("{}/accept/{}", relay_url, request.room_id);
+        let mut shutdown = request.shutdown.subscribe();
+        tokio::spawn(async move {
+            // Keep the write half alive, dropping it would close the registration
+            let (_control_write, mut control_read) = control.split();
+            loop {
+                tokio::select! {
+                    message = control_read.next() => match message {
+                        Some(Ok(Text(text))) => {
+                            if let Ok(RelayControl::Connect { conn_id }) = serde_json::from_str(&text) {
+                                tokio::spawn(bridge(
+                                    format!("{}/{}", accept_url, conn_id),
+                                    local_url.clone(),
+                                ));
+                            }
+                        }
+                        Some(Ok(_)) => {}
+                        _ => break,
+                    },
+                    _ = shutdown.recv() => break,
+                }
+            }
+        });
+
+        Ok(format!(
+            "{}/join/{}",
+            self.server.trim_end_matches('/'),
+            request.room_id
+        ))
+    }
+}
+
+async fn bridge(remote_url: String, local_url: String) {
+    let (remote, local) = match (
+        connect_async(remote_url).await,
+        connect_async(local_url).await,
+    ) {
+        (Ok((remote, _)), Ok((local, _))) => (remote, local),
+        _ => {
+            println!("Couldn't bridge relay connection");
+            return;
+        }
+    };
+    let (remote_write, remote_read) = remote.split();
+    let (local_write, local_read) = local.split();
+    tokio::select! {
+        _ = remote_read.forward(local_write) => {}
+        _ = local_read.forward(remote_write) => {}
+    }
+}
+
+/// Doesn't expose anything, members have to reach the listener directly (e.g. over the LAN).
+pub struct NoTunnel;
+
+#[async_trait]
+impl TunnelProvider for NoTunnel {
+    async fn open(&self, request: TunnelRequest) -> Result<String, String> {
+        let host = match request.local_host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+            _ => request.local_host,
+        };
+        Ok(format!("ws://{}:{}", host, request.local_port))
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomHandle {
+    pub room_id: String,
+    pub join_url: String,
+}
+
+/// Sent by a relay over the host's registration socket
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum RelayControl {
+    Connect { conn_id: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Join {
+    pub username: String,
+    pub signing_key: Vec<u8>,
+    /// X25519 key direct messages to this member are sealed to, fresh for every session
+    #[serde(default)]
+    pub direct_key: Vec<u8>,
+    /// Made with `signing_key`, so the host can't pass its own key off as the member's
+    #[serde(default)]
+    pub direct_key_signature: Vec<u8>,
+    /// Protocol version the client speaks, missing (0) on builds from before versioning
+    #[serde(default)]
+    pub protocol_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    pub pub_key: Vec<u8>,
+    /// Host's long-term identity key and its signature over both handshake keys, empty when
+    /// sent by a client
+    #[serde(default)]
+    pub identity_key: Vec<u8>,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    /// Protocol version the host speaks, so clients can tell a mismatch apart from a failed handshake
+    #[serde(default)]
+    pub protocol_version: u32,
+    /// Largest file the host lets members share, only set by the host
+    #[serde(default)]
+    pub max_file_size: u64,
+    /// Seconds messages live for in this chat, only set by the host. Members apply it to
+    /// their direct messages too.
+    #[serde(default)]
+    pub message_ttl: Option<u64>,
+}
+
+/// Longest a message may live for, longer TTLs are cut down to this
+pub const MAX_MESSAGE_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserMessage {
+    pub content: String,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+    /// Seconds until the message disappears, the chat's own TTL still applies if it's shorter
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BroadcastMessage {
+    /// Assigned by the host so later events can refer to the message
+    #[serde(default)]
+    pub id: String,
+    pub sender: String,
+    pub content: String,
+    pub created: String,
+    pub signature: Vec<u8>,
+    pub signing_key: Vec<u8>,
+    /// Set locally by the receiver once the signature has been checked, never trusted off the wire
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Set once the sender has changed the content, which is then signed with `utils::sign_edit`
+    #[serde(default)]
+    pub edited: bool,
+    /// Who reacted, by emoji
+    #[serde(default)]
+    pub reactions: BTreeMap<String, Vec<String>>,
+}
+
+/// A change to a message that's already been sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum MessageAction {
+    /// Only the sender may edit a message
+    Edit {
+        id: String,
+        content: String,
+        #[serde(default)]
+        signature: Vec<u8>,
+    },
+    /// Only the sender may delete a message
+    Delete {
+        id: String,
+    },
+    React {
+        id: String,
+        emoji: String,
+    },
+    Unreact {
+        id: String,
+        emoji: String,
+    },
+}
+
+impl MessageAction {
+    pub fn id(&self) -> &str {
+        match self {
+            MessageAction::Edit { id, .. }
+            | MessageAction::Delete { id }
+            | MessageAction::React { id, .. }
+            | MessageAction::Unreact { id, .. } => id,
+        }
+    }
+}
+
+/// Sent under the group key once the host has checked an action
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageUpdate {
+    /// Who made the change
+    pub username: String,
+    pub action: MessageAction,
+    /// Set locally for edits once the signature has been checked, never trusted off the wire
+    #[serde(default)]
+    pub verified: bool,
+}
+
+/// A message sealed to one member's key. The host routes it but can't read it unless it's the
+/// recipient.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirectMessage {
+    /// The recipient when sent to the host, the sender once the host passes it on
+    pub username: String,
+    pub ephemeral_key: Vec<u8>,
+    /// A sealed `BroadcastMessage` signed with `utils::sign_direct`
+    pub data: EncData,
+}
+
+/// A direct message as shown to either side of the conversation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DirectChat {
+    /// The other member in the conversation
+    pub peer: String,
+    pub message: BroadcastMessage,
+}
+
+/// What members are shown of a shared file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileInfo {
+    pub id: String,
+    pub sender: String,
+    pub name: String,
+    pub size: u64,
+}
+
+/// Sent under the group key. The chunks are encrypted under `key` instead, so a key rotation
+/// partway through a transfer doesn't break it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileOffer {
+    pub info: FileInfo,
+    /// SHA-256 of the whole file, checked once every chunk has arrived
+    pub hash: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileAccept {
+    pub id: String,
+    /// Filled in by the host with whoever accepted
+    #[serde(default)]
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileChunk {
+    pub id: String,
+    /// The recipient when sent to the host, the sender once the host passes it on
+    pub username: String,
+    pub index: u32,
+    pub data: EncData,
+}
+
+/// Follows the last chunk of a file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileComplete {
+    pub id: String,
+    /// The recipient when sent to the host, the sender once the host passes it on
+    pub username: String,
+}
+
+/// How far a transfer has got, in either direction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileProgress {
+    pub id: String,
+    /// Who the file is going to or coming from
+    pub peer: String,
+    pub outgoing: bool,
+    pub bytes: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JoinMessage {
+    pub joined: String,
+    pub signing_key: Vec<u8>,
+    #[serde(default)]
+    pub direct_key: Vec<u8>,
+    #[serde(default)]
+    pub direct_key_signature: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum PresenceState {
+    #[default]
+    Online,
+    Idle,
+    Typing,
+    /// Only ever set by the host, once the member has left
+    Offline,
+}
+
+/// Sent under the group key whenever someone's presence changes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Presence {
+    pub username: String,
+    pub state: PresenceState,
+}
+
+/// How often each side pings the other, and how long it waits without hearing anything
+/// before giving up on the connection. Both are in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: u64,
+    pub timeout: u64,
+}
+
+impl Heartbeat {
+    /// Pings every `interval` seconds and allows three to go unanswered
+    pub fn every(interval: u64) -> Self {
+        Heartbeat {
+            interval,
+            timeout: interval * 3,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::every(15)
+    }
+}
+
+/// Someone in the room, as listed in the roster a member gets on joining
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    pub username: String,
+    pub signing_key: Vec<u8>,
+    /// Safety number of `signing_key`
+    pub fingerprint: String,
+    /// Key to seal direct messages to, see `Join::direct_key`
+    #[serde(default)]
+    pub direct_key: Vec<u8>,
+    #[serde(default)]
+    pub direct_key_signature: Vec<u8>,
+    pub state: PresenceState,
+    pub host: bool,
+}
+
+impl Member {
+    pub fn new(
+        username: String,
+        signing_key: Vec<u8>,
+        direct_key: Vec<u8>,
+        direct_key_signature: Vec<u8>,
+        state: PresenceState,
+        host: bool,
+    ) -> Self {
+        Member {
+            direct_key,
+            direct_key_signature,
+            fingerprint: crate::utils::safety_number(&signing_key),
+            username,
+            signing_key,
+            state,
+            host,
+        }
+    }
+}
+
+/// Someone waiting for the host to let them in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JoinRequest {
+    pub username: String,
+    /// Safety number of the joiner's signing key
+    pub fingerprint: String,
+}
+
+/// Tells a member why the host removed them
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Kick {
+    pub reason: String,
+    pub banned: bool,
+}
+
+/// Sent ahead of the room closing itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShutdownWarning {
+    pub seconds_left: u64,
+    /// Set when the room is closing for being idle, so any new message calls it off
+    pub idle: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Error {
+    pub error_msg: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyMessage {
+    pub epoch: u32,
+    pub key: EncData,
+    /// Under the session key, only in the first `KeyMessage` after joining
+    #[serde(default)]
+    pub resume_token: Option<EncData>,
+}
+
+/// Sent instead of a handshake and join to take back a slot after the connection dropped
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Resume {
+    pub username: String,
+    /// The resume token under the old session key, which shows we still hold that key
+    pub token: EncData,
+    /// Frames received from the host so far, anything after these gets replayed
+    pub received: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Resumed {
+    /// The next resume token under the session key
+    pub token: EncData,
+    /// Missed frames that expired before they could be replayed. They still count towards
+    /// the frames received, so the next resume picks up in the right place.
+    pub skipped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncData {
+    pub nonce: Vec<u8>,
+    pub data: Vec<u8>,
+    pub epoch: u32,
+}
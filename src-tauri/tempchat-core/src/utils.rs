@@ -0,0 +1,602 @@
+use std::fs;
+use std::path::PathBuf;
+
+use aes_siv::{
+    aead::{Aead, OsRng},
+    Aes256SivAead, Key, KeyInit, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+use crate::structs::EncData;
+
+async fn generate_nonce() -> Nonce {
+    let mut nonce: [u8; 16] = [0; 16];
+    OsRng.fill_bytes(&mut nonce);
+    Nonce::from_slice(&nonce).to_owned()
+}
+
+/// URL layout version. Bump whenever the join URL format or key derivation changes so
+/// that links created by older builds are rejected instead of failing to decrypt.
+const JOIN_URL_VERSION: &str = "v2";
+const SALT_LEN: usize = 16;
+
+// Upper bounds accepted when parsing a join URL, so a crafted link can't make the joiner
+// allocate an unreasonable amount of memory or spin for minutes.
+const MAX_MEM_COST: u32 = 256 * 1024;
+const MAX_TIME_COST: u32 = 10;
+const MAX_PARALLELISM: u32 = 4;
+
+const IDENTITY_FILE: &str = "identity.key";
+const MEMBER_FILE: &str = "member.key";
+
+/// What a join URL decrypts to: where to connect and which host identity to expect there.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JoinTarget {
+    pub url: String,
+    pub host_fingerprint: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum KdfStrength {
+    Low,
+    #[default]
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub mem_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+impl From<KdfStrength> for KdfParams {
+    fn from(strength: KdfStrength) -> Self {
+        let (mem_cost, time_cost) = match strength {
+            KdfStrength::Low => (19 * 1024, 2),
+            KdfStrength::Medium => (64 * 1024, 3),
+            KdfStrength::High => (256 * 1024, 4),
+        };
+        KdfParams {
+            mem_cost,
+            time_cost,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    fn encode(&self) -> String {
+        format!("{}.{}.{}", self.mem_cost, self.time_cost, self.parallelism)
+    }
+
+    fn decode(encoded: &str) -> Result<Self, String> {
+        let parts: Vec<u32> = encoded
+            .split('.')
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| "Invalid key derivation parameters in URL".to_string())?;
+        if parts.len() != 3 {
+            return Err("Invalid key derivation parameters in URL".into());
+        }
+        let params = KdfParams {
+            mem_cost: parts[0],
+            time_cost: parts[1],
+            parallelism: parts[2],
+        };
+        if params.mem_cost > MAX_MEM_COST
+            || params.time_cost > MAX_TIME_COST
+            || params.parallelism > MAX_PARALLELISM
+        {
+            return Err("Key derivation parameters in URL are too expensive".into());
+        }
+        Ok(params)
+    }
+}
+
+async fn derive_url_key(
+    password: String,
+    salt: Vec<u8>,
+    params: KdfParams,
+) -> Result<Vec<u8>, String> {
+    // Argon2 is deliberately slow, keep it off the async worker threads
+    tokio::task::spawn_blocking(move || {
+        let argon_params = Params::new(
+            params.mem_cost,
+            params.time_cost,
+            params.parallelism,
+            Some(64),
+        )
+        .map_err(|_| "Invalid key derivation parameters".to_string())?;
+        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+        let mut key = vec![0; 64];
+        argon
+            .hash_password_into(password.as_bytes(), &salt, &mut key)
+            .map_err(|_| "Couldn't derive key from password".to_string())?;
+        Ok(key)
+    })
+    .await
+    .map_err(|_| "Couldn't derive key from password".to_string())?
+}
+
+pub async fn create_join_url(
+    target: JoinTarget,
+    password: String,
+    strength: KdfStrength,
+) -> Result<String, String> {
+    let params = KdfParams::from(strength);
+    let mut salt = vec![0; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let key_vec = derive_url_key(password, salt.clone(), params).await?;
+    let key: &Key<Aes256SivAead> = key_vec.as_slice().into();
+    let cipher = Aes256SivAead::new(key);
+    let nonce = generate_nonce().await;
+    let target_data = serde_json::to_string(&target).expect("Couldn't convert join target");
+    let encrypted_url_res = cipher.encrypt(&nonce, target_data.as_bytes());
+    match encrypted_url_res {
+        Ok(encrypted_url) => {
+            let hex_url = hex::encode(encrypted_url);
+            let hex_nonce = hex::encode(nonce);
+            let hex_salt = hex::encode(salt);
+            Ok(format!(
+                "temp://{}_{}_{}_{}_{}",
+                JOIN_URL_VERSION,
+                params.encode(),
+                hex_salt,
+                hex_nonce,
+                hex_url
+            ))
+        }
+        Err(aes_siv::Error) => Err("Couldn't encrypt join url".into()),
+    }
+}
+
+pub async fn parse_join_url(join_url: String, password: String) -> Result<JoinTarget, String> {
+    let join_url = join_url.replace("temp://", "");
+    let split_url: Vec<&str> = join_url.splitn(5, "_").collect();
+    if split_url.len() != 5 {
+        return Err("URL is in incorrect format".into());
+    }
+    if split_url[0] != JOIN_URL_VERSION {
+        return Err("Unsupported join URL version, ask the host for a new link".into());
+    }
+    let params = KdfParams::decode(split_url[1])?;
+    let (hex_salt, hex_nonce, hex_url) = (split_url[2], split_url[3], split_url[4]);
+    let try_salt = hex::decode(hex_salt);
+    let try_nonce = hex::decode(hex_nonce);
+    let try_url = hex::decode(hex_url);
+    if try_salt.is_err() || try_nonce.is_err() || try_url.is_err() {
+        return Err("Could not decode URL".into());
+    }
+    let salt = try_salt.unwrap();
+    if salt.len() != SALT_LEN {
+        return Err("Could not decode URL".into());
+    }
+    let nonce = try_nonce.unwrap();
+    if nonce.len() != 16 {
+        return Err("Could not decode URL".into());
+    }
+    let nonce = Nonce::from_slice(&nonce);
+    let key_vec = derive_url_key(password, salt, params).await?;
+    let key: &Key<Aes256SivAead> = key_vec.as_slice().into();
+    let cipher = Aes256SivAead::new(key);
+    let decrypt_res = cipher.decrypt(nonce, try_url.unwrap().as_slice());
+    match decrypt_res {
+        Ok(target_data) => {
+            let parsed_res = serde_json::from_slice::<JoinTarget>(&target_data);
+            if parsed_res.is_err() {
+                return Err("Couldn't decrypt URL".into());
+            }
+            Ok(parsed_res.unwrap())
+        }
+        Err(aes_siv::Error) => Err("Couldn't decrypt URL".into()),
+    }
+}
+
+pub async fn decrypt_message(
+    enc_data: &EncData,
+    cipher: &Aes256SivAead,
+) -> Result<Vec<u8>, String> {
+    let nonce = enc_data.nonce.as_slice();
+    let decrypt_res = cipher.decrypt(nonce.into(), enc_data.data.as_slice());
+    match decrypt_res {
+        Ok(decrypted) => Ok(decrypted),
+        Err(_err) => Err("Couldn't decrypt message data".into()),
+    }
+}
+
+pub async fn encrypt_message(
+    message: String,
+    cipher: &Aes256SivAead,
+    epoch: u32,
+) -> Result<EncData, String> {
+    encrypt_data(message.as_bytes(), cipher, epoch).await
+}
+
+pub async fn encrypt_data(
+    data: &[u8],
+    cipher: &Aes256SivAead,
+    epoch: u32,
+) -> Result<EncData, String> {
+    let nonce = generate_nonce().await;
+    let cipher_message_res = cipher.encrypt(&nonce, data);
+    match cipher_message_res {
+        Ok(cipher_message) => Ok(EncData {
+            nonce: nonce.to_vec(),
+            data: cipher_message,
+            epoch,
+        }),
+        Err(_err) => Err("Couldn't ecnrypt message".into()),
+    }
+}
+
+pub fn parse_handshake_key(pub_key: &[u8]) -> Result<PublicKey, String> {
+    let bytes: [u8; 32] = pub_key
+        .try_into()
+        .map_err(|_| "Invalid handshake public key".to_string())?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Derives the per-connection session cipher from an X25519 exchange. Both public keys are
+/// mixed in so each side ends up bound to the same handshake transcript.
+pub fn derive_session_cipher(
+    shared: &SharedSecret,
+    client_pub: &PublicKey,
+    host_pub: &PublicKey,
+) -> Result<Aes256SivAead, String> {
+    if !shared.was_contributory() {
+        return Err("Handshake produced a weak shared secret".into());
+    }
+    derive_cipher(shared, b"tempchat-session", client_pub, host_pub)
+}
+
+fn derive_cipher(
+    shared: &SharedSecret,
+    domain: &[u8],
+    first_pub: &PublicKey,
+    second_pub: &PublicKey,
+) -> Result<Aes256SivAead, String> {
+    let mut info = domain.to_vec();
+    info.extend_from_slice(first_pub.as_bytes());
+    info.extend_from_slice(second_pub.as_bytes());
+
+    let hkdf = Hkdf::<Sha256>::new(None, shared.as_bytes());
+    let mut key = [0u8; 64];
+    hkdf.expand(&info, &mut key)
+        .map_err(|_| "Couldn't derive session key".to_string())?;
+    let key: &Key<Aes256SivAead> = key.as_slice().into();
+    Ok(Aes256SivAead::new(key))
+}
+
+/// Encrypts `data` so only the holder of `recipient_key` can read it. `recipient_key` is the
+/// X25519 key they advertised for this session, paired with a fresh key per message, so
+/// nothing sealed now can be opened with their long-term key later. Returns the ephemeral
+/// public key with the ciphertext.
+pub async fn seal_direct(data: &[u8], recipient_key: &[u8]) -> Result<(Vec<u8>, EncData), String> {
+    let recipient_pub = parse_handshake_key(recipient_key)?;
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_pub = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&recipient_pub);
+    if !shared.was_contributory() {
+        return Err("Recipient key produced a weak shared secret".into());
+    }
+    let cipher = derive_cipher(&shared, b"tempchat-direct", &ephemeral_pub, &recipient_pub)?;
+    let sealed = encrypt_data(data, &cipher, 0).await?;
+    Ok((ephemeral_pub.as_bytes().to_vec(), sealed))
+}
+
+/// Opens a direct message sealed with `seal_direct` to our session's direct message key
+pub async fn open_direct(
+    ephemeral_key: &[u8],
+    sealed: &EncData,
+    secret: &StaticSecret,
+) -> Result<Vec<u8>, String> {
+    let own_pub = PublicKey::from(secret);
+    let ephemeral_pub = parse_handshake_key(ephemeral_key)?;
+    let shared = secret.diffie_hellman(&ephemeral_pub);
+    let cipher = derive_cipher(&shared, b"tempchat-direct", &ephemeral_pub, &own_pub)?;
+    decrypt_message(sealed, &cipher).await
+}
+
+fn signed_payload(domain: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut payload = domain.to_vec();
+    for part in data {
+        payload.extend_from_slice(part);
+    }
+    payload
+}
+
+fn verify_payload(payload: &[u8], signature: &[u8], key: &[u8]) -> bool {
+    let try_key = parse_signing_key(key);
+    let try_signature = Signature::from_slice(signature);
+    match (try_key, try_signature) {
+        (Ok(key), Ok(signature)) => key.verify_strict(payload, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+pub fn sign_message(content: &str, signing_key: &SigningKey) -> Vec<u8> {
+    let payload = signed_payload(b"tempchat-message", &[content.as_bytes()]);
+    signing_key.sign(&payload).to_bytes().to_vec()
+}
+
+pub fn parse_signing_key(key: &[u8]) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = key
+        .try_into()
+        .map_err(|_| "Invalid signing key".to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "Invalid signing key".to_string())
+}
+
+pub fn verify_message(content: &str, signature: &[u8], key: &[u8]) -> bool {
+    let payload = signed_payload(b"tempchat-message", &[content.as_bytes()]);
+    verify_payload(&payload, signature, key)
+}
+
+/// Signs a direct message together with the recipient's key so it can't be passed on to
+/// someone else as if it had been meant for them
+pub fn sign_direct(content: &str, recipient_key: &[u8], signing_key: &SigningKey) -> Vec<u8> {
+    let payload = signed_payload(b"tempchat-direct", &[recipient_key, content.as_bytes()]);
+    signing_key.sign(&payload).to_bytes().to_vec()
+}
+
+pub fn verify_direct(content: &str, recipient_key: &[u8], signature: &[u8], key: &[u8]) -> bool {
+    let payload = signed_payload(b"tempchat-direct", &[recipient_key, content.as_bytes()]);
+    verify_payload(&payload, signature, key)
+}
+
+/// Signs edited content together with the message id, so an edit can't be passed off as a new
+/// message or moved onto another one
+pub fn sign_edit(id: &str, content: &str, signing_key: &SigningKey) -> Vec<u8> {
+    let payload = signed_payload(b"tempchat-edit", &[id.as_bytes(), content.as_bytes()]);
+    signing_key.sign(&payload).to_bytes().to_vec()
+}
+
+pub fn verify_edit(id: &str, content: &str, signature: &[u8], key: &[u8]) -> bool {
+    let payload = signed_payload(b"tempchat-edit", &[id.as_bytes(), content.as_bytes()]);
+    verify_payload(&payload, signature, key)
+}
+
+/// Signs the key a member takes direct messages under for this session, so the host relaying it
+/// can't swap in one of its own
+pub fn sign_direct_key(direct_key: &[u8], signing_key: &SigningKey) -> Vec<u8> {
+    let payload = signed_payload(b"tempchat-direct-key", &[direct_key]);
+    signing_key.sign(&payload).to_bytes().to_vec()
+}
+
+pub fn verify_direct_key(direct_key: &[u8], signature: &[u8], key: &[u8]) -> bool {
+    let payload = signed_payload(b"tempchat-direct-key", &[direct_key]);
+    verify_payload(&payload, signature, key)
+}
+
+pub fn sign_handshake(
+    client_pub: &PublicKey,
+    host_pub: &PublicKey,
+    identity: &SigningKey,
+) -> Vec<u8> {
+    let payload = signed_payload(
+        b"tempchat-handshake",
+        &[client_pub.as_bytes(), host_pub.as_bytes()],
+    );
+    identity.sign(&payload).to_bytes().to_vec()
+}
+
+pub fn verify_handshake(
+    client_pub: &PublicKey,
+    host_pub: &PublicKey,
+    signature: &[u8],
+    identity_key: &[u8],
+) -> bool {
+    let payload = signed_payload(
+        b"tempchat-handshake",
+        &[client_pub.as_bytes(), host_pub.as_bytes()],
+    );
+    verify_payload(&payload, signature, identity_key)
+}
+
+pub fn fingerprint(key: &[u8]) -> Vec<u8> {
+    Sha256::digest(key).to_vec()
+}
+
+/// Renders an identity key as twelve groups of five digits that both sides can read aloud
+/// and compare out of band.
+pub fn safety_number(key: &[u8]) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update(b"tempchat-safety-number");
+    hasher.update(key);
+    let digest = hasher.finalize();
+    digest
+        .chunks_exact(5)
+        .take(12)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Loads the host's long-term identity from the given data directory, creating it on first use.
+/// Falls back to a throwaway key if the directory isn't writable so hosting still works.
+pub fn load_identity(try_dir: Option<PathBuf>) -> SigningKey {
+    SigningKey::from_bytes(&load_secret(try_dir, IDENTITY_FILE, "host identity"))
+}
+
+/// Loads the secret this device joins rooms with, kept apart from the host identity so joining
+/// never reveals who hosts. Use `room_member_key` to get the key for one room.
+pub fn load_member_secret(try_dir: Option<PathBuf>) -> [u8; 32] {
+    load_secret(try_dir, MEMBER_FILE, "member secret")
+}
+
+/// The signing key a member uses in one room. The same member gets the same key when they
+/// rejoin the room, so bans stick, while keys from different rooms can't be linked.
+pub fn room_member_key(member_secret: &[u8; 32], target: &JoinTarget) -> SigningKey {
+    let mut info = b"tempchat-member-key".to_vec();
+    info.extend_from_slice(&target.host_fingerprint);
+    info.extend_from_slice(target.url.as_bytes());
+
+    let hkdf = Hkdf::<Sha256>::new(None, member_secret);
+    let mut secret = [0u8; 32];
+    hkdf.expand(&info, &mut secret)
+        .expect("32 bytes is a valid HKDF output length");
+    SigningKey::from_bytes(&secret)
+}
+
+fn load_secret(try_dir: Option<PathBuf>, file: &str, name: &str) -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    if try_dir.is_none() {
+        println!("App data directory unavailable, using a temporary {}", name);
+        OsRng.fill_bytes(&mut secret);
+        return secret;
+    }
+    let dir = try_dir.unwrap();
+    let path = dir.join(file);
+
+    if let Ok(bytes) = fs::read(&path) {
+        if let Ok(stored) = <[u8; 32]>::try_from(bytes.as_slice()) {
+            return stored;
+        }
+        println!("Stored {} is corrupt, generating a new one", name);
+    }
+
+    OsRng.fill_bytes(&mut secret);
+    let write_res = fs::create_dir_all(&dir).and_then(|_| write_secret(&path, &secret));
+    if let Err(err) = write_res {
+        println!("Couldn't save {}: {:?}", name, err);
+    }
+    secret
+}
+
+#[cfg(unix)]
+fn write_secret(path: &std::path::Path, secret: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(secret)
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &std::path::Path, secret: &[u8]) -> std::io::Result<()> {
+    fs::write(path, secret)
+}
+
+/// Swaps an `http(s)://` scheme for the matching websocket one, the rest of the URL is left
+/// alone
+pub fn to_ws_url(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target() -> JoinTarget {
+        JoinTarget {
+            url: "wss://example.com/join/room".into(),
+            host_fingerprint: vec![7; 32],
+        }
+    }
+
+    #[tokio::test]
+    async fn join_url_round_trip() {
+        let url = create_join_url(target(), "hunter2".into(), KdfStrength::Low)
+            .await
+            .unwrap();
+        let parsed = parse_join_url(url, "hunter2".into()).await.unwrap();
+        assert_eq!(parsed.url, target().url);
+        assert_eq!(parsed.host_fingerprint, target().host_fingerprint);
+    }
+
+    #[tokio::test]
+    async fn join_url_rejects_wrong_password() {
+        let url = create_join_url(target(), "hunter2".into(), KdfStrength::Low)
+            .await
+            .unwrap();
+        assert!(parse_join_url(url, "hunter3".into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn join_url_rejects_old_version() {
+        let url = create_join_url(target(), "hunter2".into(), KdfStrength::Low)
+            .await
+            .unwrap();
+        let old = url.replacen(JOIN_URL_VERSION, "v1", 1);
+        let err = parse_join_url(old, "hunter2".into()).await.unwrap_err();
+        assert!(err.contains("Unsupported join URL version"));
+    }
+
+    #[test]
+    fn safety_number_is_stable_and_per_key() {
+        let number = safety_number(&[1; 32]);
+        assert_eq!(number, safety_number(&[1; 32]));
+        assert_ne!(number, safety_number(&[2; 32]));
+        let groups: Vec<&str> = number.split(' ').collect();
+        assert_eq!(groups.len(), 12);
+        assert!(groups
+            .iter()
+            .all(|group| group.len() == 5 && group.chars().all(|c| c.is_ascii_digit())));
+    }
+
+    #[test]
+    fn ws_url_only_swaps_the_scheme() {
+        assert_eq!(to_ws_url("http://example.com"), "ws://example.com");
+        assert_eq!(to_ws_url("https://example.com"), "wss://example.com");
+        assert_eq!(
+            to_ws_url("https://http.example.com/http"),
+            "wss://http.example.com/http"
+        );
+        assert_eq!(to_ws_url("ws://10.0.0.2:4000"), "ws://10.0.0.2:4000");
+    }
+
+    #[test]
+    fn member_keys_are_per_room() {
+        let secret = [9; 32];
+        let mut other_room = target();
+        other_room.url = "wss://example.com/join/other".into();
+        let key = room_member_key(&secret, &target());
+        assert_eq!(
+            key.to_bytes(),
+            room_member_key(&secret, &target()).to_bytes()
+        );
+        assert_ne!(
+            key.to_bytes(),
+            room_member_key(&secret, &other_room).to_bytes()
+        );
+        assert_ne!(
+            key.to_bytes(),
+            room_member_key(&[8; 32], &target()).to_bytes()
+        );
+    }
+
+    #[test]
+    fn direct_key_signature_rejects_a_swapped_key() {
+        let member = SigningKey::from_bytes(&[3; 32]);
+        let member_key = member.verifying_key().to_bytes();
+        let signature = sign_direct_key(&[4; 32], &member);
+        assert!(verify_direct_key(&[4; 32], &signature, &member_key));
+        assert!(!verify_direct_key(&[5; 32], &signature, &member_key));
+        let host = SigningKey::from_bytes(&[6; 32]);
+        let host_signature = sign_direct_key(&[5; 32], &host);
+        assert!(!verify_direct_key(&[5; 32], &host_signature, &member_key));
+    }
+}
@@ -0,0 +1,230 @@
+//! Hosts a room and joins it over a real socket, with a tunnel provider that hands out the
+//! local listener so nothing leaves the machine.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use tempchat_core::client::client::{client_exit, connect_chat, send_message};
+use tempchat_core::events::{ChatEvent, EventSink};
+use tempchat_core::files::DEFAULT_MAX_FILE_SIZE;
+use tempchat_core::server::chat::{host_chat, HostOptions};
+use tempchat_core::server::outbox::SlowConsumer;
+use tempchat_core::server::proto::{Room, Transport};
+use tempchat_core::server::socket::handle::{chat_shutdown, close_client, handle_user_message};
+use tempchat_core::server::tunnel::{TunnelProvider, TunnelRequest};
+use tempchat_core::structs::{Heartbeat, UserMessage};
+use tempchat_core::utils::KdfStrength;
+use tokio::sync::mpsc;
+
+/// Connects members straight to the listener
+struct LoopbackTunnel;
+
+#[async_trait]
+impl TunnelProvider for LoopbackTunnel {
+    async fn open(&self, request: TunnelRequest) -> Result<String, String> {
+        Ok(format!(
+            "ws://{}:{}",
+            request.local_host, request.local_port
+        ))
+    }
+}
+
+/// Collects every event so the test can wait for the ones it cares about
+struct Collect(mpsc::UnboundedSender<ChatEvent>);
+
+impl EventSink for Collect {
+    fn send(&self, _room_id: &str, event: ChatEvent) {
+        let _ = self.0.send(event);
+    }
+}
+
+fn collector() -> (Arc<dyn EventSink>, mpsc::UnboundedReceiver<ChatEvent>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (Arc::new(Collect(tx)), rx)
+}
+
+async fn wait_for<T>(
+    events: &mut mpsc::UnboundedReceiver<ChatEvent>,
+    mut matches: impl FnMut(ChatEvent) -> Option<T>,
+) -> T {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let event = events.recv().await.expect("Event sink closed");
+            if let Some(found) = matches(event) {
+                return found;
+            }
+        }
+    })
+    .await
+    .expect("Timed out waiting for an event")
+}
+
+async fn host(events: Arc<dyn EventSink>) -> (String, Arc<Room>) {
+    let options = HostOptions {
+        username: "alice".into(),
+        user_limit: 3,
+        password: "hunter2".into(),
+        kdf_strength: KdfStrength::Low,
+        transport: Transport::default(),
+        share_history: false,
+        message_ttl: None,
+        max_lifetime: None,
+        idle_timeout: None,
+        require_approval: false,
+        max_file_size: DEFAULT_MAX_FILE_SIZE,
+        heartbeat: Heartbeat::default(),
+        slow_consumer: SlowConsumer::default(),
+        tunnel_provider: Some(Box::new(LoopbackTunnel)),
+    };
+    let (handle, room) = host_chat(options, SigningKey::generate(&mut OsRng), events)
+        .await
+        .unwrap();
+    (handle.join_url, room)
+}
+
+fn message(content: &str) -> UserMessage {
+    UserMessage {
+        content: content.into(),
+        signature: Vec::new(),
+        ttl: None,
+    }
+}
+
+#[tokio::test]
+async fn member_and_host_exchange_messages() {
+    let (host_events, mut host_rx) = collector();
+    let (join_url, room) = host(host_events).await;
+
+    let (member_events, mut member_rx) = collector();
+    let member = connect_chat(
+        "bob".into(),
+        join_url,
+        "hunter2".into(),
+        [1; 32],
+        Heartbeat::default(),
+        member_events,
+    )
+    .await
+    .unwrap();
+    wait_for(&mut host_rx, |event| match event {
+        ChatEvent::Join(join) if join.joined == "bob" => Some(()),
+        _ => None,
+    })
+    .await;
+    let roster = wait_for(&mut member_rx, |event| match event {
+        ChatEvent::Roster(roster) => Some(roster),
+        _ => None,
+    })
+    .await;
+    assert!(roster
+        .iter()
+        .any(|member| member.username == "alice" && member.host));
+
+    send_message(&member, message("hi from bob")).await.unwrap();
+    let received = wait_for(&mut host_rx, |event| match event {
+        ChatEvent::Message(message) if message.sender == "bob" => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received.content, "hi from bob");
+    assert!(received.verified);
+
+    handle_user_message(&room, &message("hi from alice"), None)
+        .await
+        .unwrap();
+    let received = wait_for(&mut member_rx, |event| match event {
+        ChatEvent::Message(message) if message.sender == "alice" => Some(message),
+        _ => None,
+    })
+    .await;
+    assert_eq!(received.content, "hi from alice");
+    assert!(received.verified);
+
+    client_exit(&member).await;
+    wait_for(&mut host_rx, |event| match event {
+        ChatEvent::Exit(exit) if exit.username == "bob" => Some(()),
+        _ => None,
+    })
+    .await;
+    chat_shutdown(&room).await;
+}
+
+#[tokio::test]
+async fn join_is_refused_with_the_wrong_password_or_the_host_name() {
+    let (host_events, _host_rx) = collector();
+    let (join_url, room) = host(host_events).await;
+
+    let (member_events, _) = collector();
+    let wrong_password = connect_chat(
+        "bob".into(),
+        join_url.clone(),
+        "hunter3".into(),
+        [1; 32],
+        Heartbeat::default(),
+        member_events,
+    )
+    .await;
+    assert!(wrong_password.is_err());
+
+    let (member_events, mut member_rx) = collector();
+    let _impostor = connect_chat(
+        "alice".into(),
+        join_url,
+        "hunter2".into(),
+        [2; 32],
+        Heartbeat::default(),
+        member_events,
+    )
+    .await
+    .unwrap();
+    let err = wait_for(&mut member_rx, |event| match event {
+        ChatEvent::Error(err) => Some(err),
+        _ => None,
+    })
+    .await;
+    assert_eq!(err, "Username already taken");
+    chat_shutdown(&room).await;
+}
+
+#[tokio::test]
+async fn member_dropped_after_an_error_is_announced() {
+    let (host_events, mut host_rx) = collector();
+    let (join_url, room) = host(host_events).await;
+
+    let (member_events, _) = collector();
+    let _member = connect_chat(
+        "bob".into(),
+        join_url,
+        "hunter2".into(),
+        [1; 32],
+        Heartbeat::default(),
+        member_events,
+    )
+    .await
+    .unwrap();
+    wait_for(&mut host_rx, |event| match event {
+        ChatEvent::Join(join) if join.joined == "bob" => Some(()),
+        _ => None,
+    })
+    .await;
+
+    let uid = room
+        .chat_data
+        .lock()
+        .await
+        .peer_map
+        .iter()
+        .find(|(_, client)| client.username == "bob")
+        .map(|(uid, _)| uid.clone())
+        .unwrap();
+    close_client(&room, &uid).await;
+    wait_for(&mut host_rx, |event| match event {
+        ChatEvent::Exit(exit) if exit.username == "bob" => Some(()),
+        _ => None,
+    })
+    .await;
+    chat_shutdown(&room).await;
+}
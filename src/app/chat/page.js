@@ -1,39 +1,80 @@
 "use client";
 
 import { useSearchParams } from "next/navigation";
-import { Divider, Button, Textarea, Modal, ModalBody, ModalContent, ModalHeader, ModalFooter } from "@nextui-org/react";
+import { Divider, Button, Input, Textarea, Modal, ModalBody, ModalContent, ModalHeader, ModalFooter } from "@nextui-org/react";
 import { FaRegCopy } from "react-icons/fa";
 import { IoMdSend } from "react-icons/io";
+import { FaPaperclip } from "react-icons/fa6";
 import { useRef, useState } from "react";
 import { IoMdClose } from "react-icons/io";
 import ChatBubble from "@/components/ChatBubble";
 import { listen, emit } from "@tauri-apps/api/event";
+import { invoke } from "@tauri-apps/api/tauri";
 import { useEffect } from "react";
 import JoinLeave from "@/components/JoinLeave";
-import { FaCheck } from "react-icons/fa6";
+import FileCard from "@/components/FileCard";
+import MemberList from "@/components/MemberList";
+import { FaCheck, FaShieldHalved, FaUserSlash } from "react-icons/fa6";
 import { isPermissionGranted, requestPermission, sendNotification } from '@tauri-apps/api/notification';
 
 export default function ChatRoom() {
     const search = useSearchParams()
     const room_url = search.get('roomURL')
+    const room_id = search.get('roomId')
     const username = search.get('username')
     const isHost = search.get("type") === "host"
 
     const [message, setMessage] = useState("")
+    const [recipient, setRecipient] = useState("")
+    const [directError, setDirectError] = useState("")
+    const [transfers, setTransfers] = useState({})
     const [messages, setMessages] = useState([])
     const [isShutdown, setShutdown] = useState(false)
     const [errorModal, setErrorModal] = useState(false)
     const [errorContent, setErrorContent] = useState("")
     const [copied, setCopied] = useState(false)
+    const [safetyNumber, setSafetyNumber] = useState("")
+    const [safetyModal, setSafetyModal] = useState(false)
+    const [closingIn, setClosingIn] = useState(null)
+    const [moderateModal, setModerateModal] = useState(false)
+    const [moderateUser, setModerateUser] = useState("")
+    const [moderateReason, setModerateReason] = useState("")
+    const [moderateError, setModerateError] = useState("")
+    const [kicked, setKicked] = useState(null)
+    const [joinRequests, setJoinRequests] = useState([])
+    const [waiting, setWaiting] = useState(false)
+    const [editing, setEditing] = useState(null)
+    const [members, setMembers] = useState({})
+    const [reconnecting, setReconnecting] = useState(false)
+    const lastTyping = useRef(0)
 
     const msgRef = useRef(null)
+    const fileRef = useRef(null)
 
     function sendMessage(e) {
         if(message.length === 0) {
             return
         }
 
-        emit("host-message", { content: message })
+        if(editing) {
+            updateMessage({ type: "edit", id: editing, content: message })
+            setEditing(null)
+            setMessage("")
+            return
+        }
+
+        if(recipient.length > 0) {
+            invoke('send_direct_message', { isHost: isHost, roomId: room_id, recipient: recipient, content: message })
+                .then(() => {
+                    setMessage("")
+                    setDirectError("")
+                }).catch((err) => {
+                    setDirectError(err)
+                })
+            return
+        }
+
+        emit(`host-message:${room_id}`, { content: message })
             .then(() => {
                 setMessage("")
             }).catch((e) => {
@@ -41,14 +82,95 @@ export default function ChatRoom() {
             })  
     }
 
+    const typing = Object.keys(members).filter((name) => name !== username && members[name].state === "typing")
+
+    function setPresence(state) {
+        invoke('set_presence', { isHost: isHost, roomId: room_id, state: state }).catch((err) => {
+            console.log(err)
+        })
+    }
+
+    // Typing is only re-sent every couple of seconds, the host drops anything faster anyway.
+    // Direct messages stay quiet, everyone would see it otherwise.
+    function changeMessage(value) {
+        setMessage(value)
+        const now = Date.now()
+        if(value.length > 0 && recipient.length === 0 && now - lastTyping.current > 2000) {
+            lastTyping.current = now
+            setPresence("typing")
+        }
+    }
+
+    function refreshMembers() {
+        invoke('get_members', { isHost: isHost, roomId: room_id }).then((list) => {
+            setMembers(Object.fromEntries(list.map((member) => [member.username, member])))
+        }).catch((err) => {
+            console.log(err)
+        })
+    }
+
+    // Only moves `from` to `to`, so a newer state isn't overwritten
+    function changeState(name, from, to) {
+        setMembers((prev) => prev[name] && (!from || prev[name].state === from) ? { ...prev, [name]: { ...prev[name], state: to } } : prev)
+    }
+
+    function setMemberState(name, state) {
+        changeState(name, null, state)
+        // Typing fades out on its own if nothing follows it
+        if(state === "typing") {
+            setTimeout(() => changeState(name, "typing", "online"), 5000)
+        }
+    }
+
+    function updateMessage(action) {
+        invoke('update_message', { isHost: isHost, roomId: room_id, action: action }).catch((err) => {
+            setErrorContent(err)
+            setErrorModal(true)
+        })
+    }
+
+    function toggleReaction(val, emoji) {
+        const reacted = val.reactions?.[emoji]?.includes(username)
+        updateMessage({ type: reacted ? "unreact" : "react", id: val.id, emoji: emoji })
+    }
+
+    function startEdit(val) {
+        setEditing(val.id)
+        setRecipient("")
+        setMessage(val.content)
+    }
+
+    function shareFile(e) {
+        const file = e.target.files[0]
+        e.target.value = ""
+        if(!file) {
+            return
+        }
+        file.arrayBuffer().then((buffer) => {
+            return invoke('send_file', { isHost: isHost, roomId: room_id, name: file.name, data: Array.from(new Uint8Array(buffer)) })
+        }).then(() => {
+            setDirectError("")
+        }).catch((err) => {
+            setDirectError(err)
+        })
+    }
+
+    function acceptFile(info) {
+        setTransfers((prev) => ({ ...prev, [info.id]: { bytes: 0, size: info.size } }))
+        invoke('accept_file', { isHost: isHost, roomId: room_id, fileId: info.id }).catch((err) => {
+            setTransfers((prev) => ({ ...prev, [info.id]: { ...prev[info.id], failed: true } }))
+            setDirectError(err)
+        })
+    }
+
     function leaveSession(exit) {
         isHost ?
-            emit("shutdown").then(() => {
+            emit(`shutdown:${room_id}`).then(() => {
                 if(!exit) {
                     window.location.href = '/'
                 }
             }) :
-            emit("client_exit").then(() => {
+            emit(`client_exit:${room_id}`).then(() => {
                 if(!exit) {
                     window.location.href = '/'
                 }
@@ -61,6 +183,39 @@ export default function ChatRoom() {
         setTimeout(() => { setCopied(false) }, 1500)
     }
 
+    function showSafetyNumber() {
+        invoke('get_safety_number', { isHost: isHost, roomId: room_id }).then((number) => {
+            setSafetyNumber(number)
+            setSafetyModal(true)
+        }).catch((err) => {
+            setErrorContent(err)
+            setErrorModal(true)
+        })
+    }
+
+    function closeModerate() {
+        setModerateModal(false)
+        setModerateUser("")
+        setModerateReason("")
+        setModerateError("")
+    }
+
+    function moderate(command) {
+        invoke(command, { roomId: room_id, username: moderateUser, reason: moderateReason }).then(() => {
+            closeModerate()
+        }).catch((err) => {
+            setModerateError(err)
+        })
+    }
+
+    function answerJoin(request, approve) {
+        invoke(approve ? 'approve_join' : 'deny_join', { roomId: room_id, username: request.username }).catch((err) => {
+            console.log(err)
+        }).finally(() => {
+            setJoinRequests((prev) => prev.filter((val) => val.username !== request.username))
+        })
+    }
+
     async function hasPermission() {
         const granted = await isPermissionGranted()
         if(!granted) {
@@ -70,17 +225,54 @@ export default function ChatRoom() {
         return granted
     }
 
+    useEffect(() => {
+        if(!closingIn) { return }
+        const timer = setTimeout(() => {
+            setClosingIn((prev) => prev && prev.seconds > 1 ? { ...prev, seconds: prev.seconds - 1 } : null)
+        }, 1000)
+        return () => clearTimeout(timer)
+    }, [closingIn])
+
     useEffect(() => {
         if(msgRef.current) {
             msgRef.current.scrollTop = msgRef.current.scrollHeight;
         }
     }, [messages])
 
+    function listenRoom(event, handler) {
+        return listen(event, (e) => {
+            if(e.payload.roomId !== room_id) {
+                return
+            }
+            handler(e.payload.data)
+        })
+    }
+
     useEffect(() => {
         if(!window) { return }
-        const message_unlisten = listen('new-message', (e) => {
-            const content = JSON.parse(e.payload)
+        refreshMembers()
+
+        const onBlur = () => setPresence("idle")
+        const onFocus = () => setPresence("online")
+        window.addEventListener("blur", onBlur)
+        window.addEventListener("focus", onFocus)
+
+        const roster_unlisten = listenRoom('roster', (data) => {
+            const list = JSON.parse(data)
+            setMembers(Object.fromEntries(list.map((member) => [member.username, member])))
+        })
+
+        const presence_unlisten = listenRoom('presence', (data) => {
+            const presence = JSON.parse(data)
+            setMemberState(presence.username, presence.state)
+        })
+
+        const message_unlisten = listenRoom('new-message', (data) => {
+            const content = JSON.parse(data)
             setMessages((prev) => [...prev, content])
+            changeState(content.sender, "typing", "online")
+            // Any message keeps an idle room open
+            setClosingIn((prev) => prev && prev.idle ? null : prev)
             hasPermission().then((permission) => {
                 if(permission && !window.document.hasFocus()) {
                     sendNotification("New message")
@@ -88,31 +280,143 @@ export default function ChatRoom() {
             })
         })
 
-        const join_unlisten = listen('join', (e) => {
-            const content = JSON.parse(e.payload)
+        const direct_unlisten = listenRoom('direct-message', (data) => {
+            const content = JSON.parse(data)
             setMessages((prev) => [...prev, content])
+            hasPermission().then((permission) => {
+                if(permission && !window.document.hasFocus() && content.message.sender === content.peer) {
+                    sendNotification(`Direct message from ${content.peer}`)
+                }
+            })
         })
 
-        const error_unlisten = listen('error', (e) => {
-            setErrorContent(e.payload)
-            setErrorModal(true)
+        const offer_unlisten = listenRoom('file-offer', (data) => {
+            const info = JSON.parse(data)
+            setMessages((prev) => [...prev, { file: info }])
         })
 
-        const shutdown_unlisten = listen('shutdown', (e) => {
-            if(!isHost) {
-                setShutdown(true)
+        const progress_unlisten = listenRoom('file-progress', (data) => {
+            const progress = JSON.parse(data)
+            // Our own uploads go to each member separately, only downloads get a progress bar
+            if(progress.outgoing) {
+                return
             }
+            setTransfers((prev) => ({ ...prev, [progress.id]: { ...prev[progress.id], bytes: progress.bytes, size: progress.size } }))
         })
 
-        const exit_unlisten = listen('client_exit', (e) => {
-            const content = JSON.parse(e.payload)
+        const received_unlisten = listenRoom('file-received', (data) => {
+            const saved = JSON.parse(data)
+            setTransfers((prev) => ({ ...prev, [saved.id]: { ...prev[saved.id], saved: saved.path, failed: !saved.path } }))
+        })
+
+        const warning_unlisten = listenRoom('shutdown-warning', (data) => {
+            const warning = JSON.parse(data)
+            setClosingIn({ seconds: warning.seconds_left, idle: warning.idle })
+        })
+
+        const expire_unlisten = listenRoom('expire-message', (id) => {
+            setMessages((prev) => prev.filter((val) => val.id !== id && val.message?.id !== id))
+        })
+
+        const update_unlisten = listenRoom('message-update', (data) => {
+            const update = JSON.parse(data)
+            const action = update.action
+            if(action.type === "delete") {
+                setMessages((prev) => prev.filter((val) => val.id !== action.id))
+                return
+            }
+            setMessages((prev) => prev.map((val) => {
+                if(val.id !== action.id) {
+                    return val
+                }
+                if(action.type === "edit") {
+                    return { ...val, content: action.content, edited: true, verified: update.verified }
+                }
+                const reactions = { ...val.reactions }
+                const users = (reactions[action.emoji] || []).filter((user) => user !== update.username)
+                if(action.type === "react") {
+                    users.push(update.username)
+                }
+                if(users.length > 0) {
+                    reactions[action.emoji] = users
+                } else {
+                    delete reactions[action.emoji]
+                }
+                return { ...val, reactions: reactions }
+            }))
+        })
+
+        const join_unlisten = listenRoom('join', (data) => {
+            const content = JSON.parse(data)
+            setMessages((prev) => [...prev, content])
+            refreshMembers()
+            setWaiting(false)
+        })
+
+        const error_unlisten = listenRoom('error', (data) => {
+            setErrorContent(data)
+            setErrorModal(true)
+        })
+
+        const request_unlisten = listenRoom('join-request', (data) => {
+            const request = JSON.parse(data)
+            setJoinRequests((prev) => [...prev, request])
+            hasPermission().then((permission) => {
+                if(permission && !window.document.hasFocus()) {
+                    sendNotification(`${request.username} wants to join`)
+                }
+            })
+        })
+
+        const disconnected_unlisten = listenRoom('disconnected', () => {
+            setReconnecting(true)
+        })
+
+        const reconnected_unlisten = listenRoom('reconnected', () => {
+            setReconnecting(false)
+        })
+
+        const pending_unlisten = listenRoom('join-pending', () => {
+            setWaiting(true)
+        })
+
+        const kicked_unlisten = listenRoom('kicked', (data) => {
+            setKicked(JSON.parse(data))
+        })
+
+        const shutdown_unlisten = listenRoom('shutdown', () => {
+            setShutdown(true)
+        })
+
+        const exit_unlisten = listenRoom('client_exit', (data) => {
+            const content = JSON.parse(data)
             setMessages((prev) => [...prev, { exit: content}])
+            setMembers((prev) => {
+                const { [content.username]: _, ...rest } = prev
+                return rest
+            })
         })  
 
         return () => {
+            window.removeEventListener("blur", onBlur)
+            window.removeEventListener("focus", onFocus)
+            roster_unlisten.then(f => f())
+            presence_unlisten.then(f => f())
             message_unlisten.then(f => f())
+            direct_unlisten.then(f => f())
+            offer_unlisten.then(f => f())
+            progress_unlisten.then(f => f())
+            received_unlisten.then(f => f())
+            expire_unlisten.then(f => f())
+            update_unlisten.then(f => f())
+            warning_unlisten.then(f => f())
             join_unlisten.then(f => f())
             error_unlisten.then(f => f())
+            request_unlisten.then(f => f())
+            pending_unlisten.then(f => f())
+            disconnected_unlisten.then(f => f())
+            reconnected_unlisten.then(f => f())
+            kicked_unlisten.then(f => f())
             shutdown_unlisten.then(f => f())
             exit_unlisten.then(f => f())
         }
@@ -131,6 +435,27 @@ export default function ChatRoom() {
                     >
                         {copied ? <FaCheck color="purple"/> : "Copy Join URL"}
                     </Button>
+                    <Button
+                        color="primary"
+                        variant="flat"
+                        className="ml-3 text-white"
+                        startContent={<FaShieldHalved color="purple"/>}
+                        onClick={showSafetyNumber}
+                    >
+                        Safety Number
+                    </Button>
+                    {
+                        isHost &&
+                        <Button
+                            color="primary"
+                            variant="flat"
+                            className="ml-3 text-white"
+                            startContent={<FaUserSlash color="purple"/>}
+                            onClick={() => setModerateModal(true)}
+                        >
+                            Moderate
+                        </Button>
+                    }
                 </h1>
                 <Button 
                     color="danger" 
@@ -144,6 +469,41 @@ export default function ChatRoom() {
             </div>
             
             <Divider className="w-[80vw]"/>
+            <MemberList members={members} username={username}/>
+            {
+                closingIn &&
+                <p className="w-[80vw] mt-2 text-sm text-danger">
+                    This chat closes in {closingIn.seconds} seconds{closingIn.idle ? " unless someone sends a message" : ""}
+                </p>
+            }
+            {
+                reconnecting &&
+                <p className="w-[80vw] mt-2 text-sm text-warning">
+                    Lost the connection to the host, reconnecting...
+                </p>
+            }
+            {
+                waiting &&
+                <p className="w-[80vw] mt-2 text-sm text-gray-400">
+                    Waiting for the host to let you in
+                </p>
+            }
+            {
+                joinRequests.map((request) => (
+                    <div className="flex w-[80vw] mt-2 items-center" key={request.username}>
+                        <p className="text-sm">
+                            <span className="font-bold">{request.username}</span> wants to join
+                            <span className="font-mono text-gray-400 ml-2">{request.fingerprint}</span>
+                        </p>
+                        <Button size="sm" color="primary" className="ml-auto" onPress={() => answerJoin(request, true)}>
+                            Let In
+                        </Button>
+                        <Button size="sm" color="danger" variant="flat" className="ml-2" onPress={() => answerJoin(request, false)}>
+                            Deny
+                        </Button>
+                    </div>
+                ))
+            }
             <div className="w-[80vw] max-h-[80vh] pb-20 mt-3 overflow-y-scroll scroll-smooth" ref={msgRef}>
                 {
                     messages.map((val, i) => {
@@ -151,14 +511,43 @@ export default function ChatRoom() {
                             return (<JoinLeave username={val.joinMessage.joined} key={i} isJoin={true}/>)                            
                         } else if(val.exit) {
                             return (<JoinLeave username={val.exit.username} key={i} isJoin={false}/>)
+                        } else if(val.file) {
+                            return (<FileCard info={val.file} self={val.file.sender === username} transfer={transfers[val.file.id]} onAccept={() => acceptFile(val.file)} key={i}/>)
+                        } else if(val.peer) {
+                            const incoming = val.message.sender === val.peer
+                            return (<ChatBubble time={val.message.created} author={val.message.sender} content={val.message.content} verified={val.message.verified} self={!incoming} note={incoming ? "Direct" : `Direct to ${val.peer}`} key={i}/>)
                         } else {
-                            return (<ChatBubble time={val.created} author={val.sender} content={val.content} self={val.sender === username ? true : false} key={i}/>)
+                            return (
+                                <ChatBubble
+                                    time={val.created}
+                                    author={val.sender}
+                                    content={val.content}
+                                    verified={val.verified}
+                                    self={val.sender === username ? true : false}
+                                    edited={val.edited}
+                                    reactions={val.reactions}
+                                    username={username}
+                                    onEdit={() => startEdit(val)}
+                                    onDelete={() => updateMessage({ type: "delete", id: val.id })}
+                                    onReact={(emoji) => toggleReaction(val, emoji)}
+                                    key={i}
+                                />
+                            )
                         }
                     })
                 }
             </div>
             <div className="absolute bottom-0 pb-10 bg-background">
                 <div className="flex items-center">
+                    <Input
+                        placeholder="Everyone"
+                        label="To"
+                        size="sm"
+                        variant="faded"
+                        value={recipient}
+                        onValueChange={setRecipient}
+                        className="w-40 mr-3"
+                    />
                     <Textarea
                         placeholder="Send your message"
                         maxLength={5000}
@@ -166,8 +555,8 @@ export default function ChatRoom() {
                         minRows={1}
                         variant="faded"
                         value={message}
-                        onValueChange={setMessage}
-                        className="w-[80vw] mr-3"
+                        onValueChange={changeMessage}
+                        className="w-[calc(80vw-14.25rem)] mr-3"
                         onKeyDown={(e) => {
                             if(e.key === "Enter" && !e.shiftKey) {
                                 e.preventDefault();
@@ -175,12 +564,19 @@ export default function ChatRoom() {
                             }
                         }}
                     />
+                    <input type="file" className="hidden" ref={fileRef} onChange={shareFile}/>
+                    <Button isIconOnly color="primary" variant="flat" className="mr-3" onClick={() => fileRef.current?.click()}>
+                        <FaPaperclip color="purple" className="size-4"/>
+                    </Button>
                     <Button color="primary" onClick={sendMessage} onPress={null}>
                         <IoMdSend className="size-5"/>
                     </Button>
                 </div>
                 <h3 className="text-gray-400 text-xs mt-2 ml-2">
+                    {typing.length > 0 && <span className="mr-3">{typing.join(", ")} {typing.length === 1 ? "is" : "are"} typing...</span>}
+                    {editing && <span className="mr-3 cursor-pointer" onClick={() => { setEditing(null); setMessage("") }}>Editing, click to cancel</span>}
                     {message.length.toLocaleString()}/5,000
+                    {directError && <span className="text-danger ml-3">{directError}</span>}
                 </h3>
             </div>
             <Modal
@@ -190,7 +586,7 @@ export default function ChatRoom() {
                 <ModalContent>
                     <ModalHeader>Chat Closed</ModalHeader>
                     <ModalBody>
-                        The chat lobby has been closed by the host.
+                        {isHost ? "The chat reached its time limit and closed itself." : "The chat lobby has been closed by the host."}
                     </ModalBody>
                     <ModalFooter>
                         <Button 
@@ -205,6 +601,76 @@ export default function ChatRoom() {
                     </ModalFooter>
                 </ModalContent>
             </Modal>
+            <Modal
+                isOpen={kicked !== null}
+                isDismissable={false}
+            >
+                <ModalContent>
+                    <ModalHeader>{kicked?.banned ? "Banned From Chat" : "Removed From Chat"}</ModalHeader>
+                    <ModalBody>
+                        <p>The host removed you from this chat{kicked?.banned ? " and you can't rejoin it" : ""}.</p>
+                        {kicked?.reason && <p className="text-gray-400 text-sm">Reason: {kicked.reason}</p>}
+                    </ModalBody>
+                    <ModalFooter>
+                        <Button 
+                            color="primary" 
+                            onPress={() => {
+                                setKicked(null)
+                                window.location.href = "/"
+                            }}
+                        >
+                            Continue
+                        </Button>
+                    </ModalFooter>
+                </ModalContent>
+            </Modal>
+            <Modal
+                isOpen={moderateModal}
+                onClose={closeModerate}
+            >
+                <ModalContent>
+                    <ModalHeader>Remove a Member</ModalHeader>
+                    <ModalBody>
+                        <Input
+                            label="Username"
+                            value={moderateUser}
+                            onValueChange={setModerateUser}
+                        />
+                        <Input
+                            label="Reason (optional)"
+                            value={moderateReason}
+                            onValueChange={setModerateReason}
+                        />
+                        <p className="text-gray-400 text-sm">Banned members can't rejoin this chat, even under a different name.</p>
+                        {moderateError && <p className="text-danger text-sm">{moderateError}</p>}
+                    </ModalBody>
+                    <ModalFooter>
+                        <Button color="warning" isDisabled={!moderateUser} onPress={() => moderate('kick_member')}>
+                            Kick
+                        </Button>
+                        <Button color="danger" isDisabled={!moderateUser} onPress={() => moderate('ban_member')}>
+                            Ban
+                        </Button>
+                    </ModalFooter>
+                </ModalContent>
+            </Modal>
+            <Modal
+                isOpen={safetyModal}
+                onClose={() => setSafetyModal(false)}
+            >
+                <ModalContent>
+                    <ModalHeader>Host Safety Number</ModalHeader>
+                    <ModalBody>
+                        <p className="font-mono text-lg">{safetyNumber}</p>
+                        <p className="text-gray-400 text-sm">Compare this with the host over another channel. If it matches, you are talking to the real host.</p>
+                    </ModalBody>
+                    <ModalFooter>
+                        <Button color="primary" onPress={() => setSafetyModal(false)}>
+                            Close
+                        </Button>
+                    </ModalFooter>
+                </ModalContent>
+            </Modal>
             <Modal
                 isOpen={errorModal}
                 isDismissable={false}
@@ -1,7 +1,7 @@
 "use client";
 
 import React, { useState, useEffect } from "react";
-import { Input, Button, CircularProgress, Modal, ModalContent, ModalHeader, ModalBody, ModalFooter } from "@nextui-org/react";
+import { Input, Button, CircularProgress, Modal, ModalContent, ModalHeader, ModalBody, ModalFooter, Select, SelectItem, Switch } from "@nextui-org/react";
 import { generateUsername } from "unique-username-generator";
 import { TfiReload } from "react-icons/tfi";
 import { invoke } from "@tauri-apps/api/tauri";
@@ -14,7 +14,7 @@ export default function Handle() {
     const params = useSearchParams();
     const isCreate = params.get("type") === "create";
 
-    const url_regex = new RegExp("(temp:\/\/[a-f0-9]+_[a-f0-9]+)")
+    const url_regex = new RegExp("(temp:\/\/v[0-9]+_[0-9.]+_[a-f0-9]+_[a-f0-9]+_[a-f0-9]+)")
     const [invalid, setInvalid] = useState(false);
     const [url, setUrl] = useState("");
 
@@ -28,6 +28,14 @@ export default function Handle() {
     const [error, setError] = useState("");
 
     const [password, setPassword] = useState("");
+    const [kdfStrength, setKdfStrength] = useState("medium");
+    const [lanOnly, setLanOnly] = useState(false);
+    const [shareHistory, setShareHistory] = useState(false);
+    const [messageTtl, setMessageTtl] = useState("0");
+    const [maxLifetime, setMaxLifetime] = useState("0");
+    const [idleTimeout, setIdleTimeout] = useState("0");
+    const [requireApproval, setRequireApproval] = useState(false);
+    const [maxFileSize, setMaxFileSize] = useState("10485760");
     const [passwordInvalid, setPasswordInvalid] = useState(false);
 
     useEffect(() => {
@@ -66,9 +74,9 @@ export default function Handle() {
         }
         setPasswordInvalid(false)
         setLoading(true)
-        invoke('create_chat', { username: username, userLimit: limit, password: password }).then((url) => {
+        invoke('create_chat', { username: username, userLimit: limit, password: password, kdfStrength: kdfStrength, transport: { type: lanOnly ? "lan" : "tunnel" }, shareHistory: shareHistory, messageTtl: parseInt(messageTtl), maxLifetime: parseInt(maxLifetime), idleTimeout: parseInt(idleTimeout), requireApproval: requireApproval, maxFileSize: parseInt(maxFileSize) }).then((handle) => {
             setLoading(false);
-            window.location.href = `/chat?roomURL=${url}&username=${username}&type=host`
+            window.location.href = `/chat?roomURL=${handle.joinUrl}&roomId=${handle.roomId}&username=${username}&type=host`
         }).catch((err) => {
             setError(err)
             setModalError(true)
@@ -87,9 +95,9 @@ export default function Handle() {
         }
         setPasswordInvalid(false)
         setLoading(true)
-        invoke('join_chat', { username: username, chatUrl: url, password: password }).then((e) => {
+        invoke('join_chat', { username: username, chatUrl: url, password: password }).then((handle) => {
             setLoading(false)
-            window.location.href = `/chat?roomURL=${url}&username=${username}&type=client`
+            window.location.href = `/chat?roomURL=${handle.joinUrl}&roomId=${handle.roomId}&username=${username}&type=client`
         }).catch((err) => {
             setError(err)
             setModalError(true)
@@ -153,6 +161,107 @@ export default function Handle() {
                     isInvalid={passwordInvalid}
                     onChange={(e) => setPassword(e.currentTarget.value)}
                 />
+                {
+                    isCreate &&
+                    <Select
+                        size="lg"
+                        label="Link Strength"
+                        variant="faded"
+                        description="Stronger links take longer to create and join, but are harder to crack"
+                        className="max-w-[40vw] mt-10"
+                        selectedKeys={[kdfStrength]}
+                        onChange={(e) => setKdfStrength(e.target.value || "medium")}
+                    >
+                        <SelectItem key="low" value="low">Low</SelectItem>
+                        <SelectItem key="medium" value="medium">Medium</SelectItem>
+                        <SelectItem key="high" value="high">High</SelectItem>
+                    </Select>
+                }
+                {
+                    isCreate &&
+                    <Select
+                        size="lg"
+                        label="Disappearing Messages"
+                        variant="faded"
+                        description="Messages are removed for everyone once this time has passed"
+                        className="max-w-[40vw] mt-6"
+                        selectedKeys={[messageTtl]}
+                        onChange={(e) => setMessageTtl(e.target.value || "0")}
+                    >
+                        <SelectItem key="0" value="0">Off</SelectItem>
+                        <SelectItem key="30" value="30">30 seconds</SelectItem>
+                        <SelectItem key="300" value="300">5 minutes</SelectItem>
+                        <SelectItem key="3600" value="3600">1 hour</SelectItem>
+                        <SelectItem key="86400" value="86400">1 day</SelectItem>
+                    </Select>
+                }
+                {
+                    isCreate &&
+                    <Select
+                        size="lg"
+                        label="Close Chat After"
+                        variant="faded"
+                        className="max-w-[40vw] mt-6"
+                        selectedKeys={[maxLifetime]}
+                        onChange={(e) => setMaxLifetime(e.target.value || "0")}
+                    >
+                        <SelectItem key="0" value="0">Never</SelectItem>
+                        <SelectItem key="900" value="900">15 minutes</SelectItem>
+                        <SelectItem key="3600" value="3600">1 hour</SelectItem>
+                        <SelectItem key="21600" value="21600">6 hours</SelectItem>
+                        <SelectItem key="86400" value="86400">1 day</SelectItem>
+                    </Select>
+                }
+                {
+                    isCreate &&
+                    <Select
+                        size="lg"
+                        label="Close When Idle For"
+                        variant="faded"
+                        className="max-w-[40vw] mt-6"
+                        selectedKeys={[idleTimeout]}
+                        onChange={(e) => setIdleTimeout(e.target.value || "0")}
+                    >
+                        <SelectItem key="0" value="0">Never</SelectItem>
+                        <SelectItem key="300" value="300">5 minutes</SelectItem>
+                        <SelectItem key="900" value="900">15 minutes</SelectItem>
+                        <SelectItem key="3600" value="3600">1 hour</SelectItem>
+                    </Select>
+                }
+                {
+                    isCreate &&
+                    <Select
+                        size="lg"
+                        label="Largest Shared File"
+                        variant="faded"
+                        className="max-w-[40vw] mt-6"
+                        selectedKeys={[maxFileSize]}
+                        onChange={(e) => setMaxFileSize(e.target.value || "0")}
+                    >
+                        <SelectItem key="0" value="0">No file sharing</SelectItem>
+                        <SelectItem key="1048576" value="1048576">1 MB</SelectItem>
+                        <SelectItem key="10485760" value="10485760">10 MB</SelectItem>
+                        <SelectItem key="26214400" value="26214400">25 MB</SelectItem>
+                    </Select>
+                }
+                {
+                    isCreate &&
+                    <Switch className="mt-6" isSelected={lanOnly} onValueChange={setLanOnly}>
+                        Local network only (no tunnel)
+                    </Switch>
+                }
+                {
+                    isCreate &&
+                    <Switch className="mt-3" isSelected={shareHistory} onValueChange={setShareHistory}>
+                        Show earlier messages to people who join later
+                    </Switch>
+                }
+                {
+                    isCreate &&
+                    <Switch className="mt-3" isSelected={requireApproval} onValueChange={setRequireApproval}>
+                        Approve each person before they can join
+                    </Switch>
+                }
                 <Button color="primary" className="mt-3 pr-10 pl-10 pt-6 pb-6 font-bold" onClick={isCreate ? handleCreate : handleJoin}>
                     {
                         loading ?
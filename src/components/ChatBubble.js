@@ -1,13 +1,48 @@
-export default function ChatBubble({ author, time, content, self }) {
+import { FaPen, FaTrash } from "react-icons/fa6";
+
+const REACTIONS = ["👍", "❤️", "😂", "😮", "😢"]
+
+export default function ChatBubble({ author, time, content, self, verified, note, edited, reactions, username, onEdit, onDelete, onReact }) {
     return (
-        <div className={`flex items-start gap-x-2.5 mt-5 ${self ? 'justify-end pr-5' : 'justify-start pl-5'} break-words mb-5`}>
+        <div className={`group flex items-start gap-x-2.5 mt-5 ${self ? 'justify-end pr-5' : 'justify-start pl-5'} break-words mb-5`}>
             <div className={`flex flex-col w-full max-w-[250px] leading-1.5 p-4 ${self ? "bg-primary rounded-s-xl rounded-br-xl" : "bg-[#454545] rounded-e-xl rounded-es-xl"}`}>
                 <div className="flex items-center space-x-2 rtl:space-x-reverse">
                     <span className="text-sm font-semibold text-white">{author}</span>
+                    { note && <span className="text-xs text-gray-300">{note}</span> }
+                    { !verified && <span className="text-xs font-semibold text-warning">Unverified</span> }
+                    { self && onEdit && <FaPen className="size-3 cursor-pointer text-gray-300 hidden group-hover:block" onClick={onEdit}/> }
+                    { self && onDelete && <FaTrash className="size-3 cursor-pointer text-gray-300 hidden group-hover:block" onClick={onDelete}/> }
                 </div>
                 <p className="text-sm font-normal text-white whitespace-pre-wrap">{content}</p>
-                <span className="text-sm font-normal text-gray-400 text-right">{time}</span>
+                <span className="text-sm font-normal text-gray-400 text-right">{edited && "Edited · "}{time}</span>
+                {
+                    reactions && Object.keys(reactions).length > 0 &&
+                    <div className="flex flex-wrap gap-1 mt-1">
+                        {
+                            Object.entries(reactions).map(([emoji, users]) => (
+                                <span
+                                    key={emoji}
+                                    title={users.join(", ")}
+                                    className={`text-xs px-1.5 rounded-full cursor-pointer ${users.includes(username) ? "bg-secondary" : "bg-[#2a2a2a]"}`}
+                                    onClick={() => onReact && onReact(emoji)}
+                                >
+                                    {emoji} {users.length}
+                                </span>
+                            ))
+                        }
+                    </div>
+                }
+                {
+                    onReact &&
+                    <div className="hidden group-hover:flex gap-1 mt-1">
+                        {
+                            REACTIONS.map((emoji) => (
+                                <span key={emoji} className="text-xs cursor-pointer" onClick={() => onReact(emoji)}>{emoji}</span>
+                            ))
+                        }
+                    </div>
+                }
             </div>
         </div>
     )
-}
\ No newline at end of file
+}
@@ -0,0 +1,46 @@
+import { Button, Progress } from "@nextui-org/react";
+import { FaFileArrowDown } from "react-icons/fa6";
+
+function formatSize(bytes) {
+    if(bytes < 1024) {
+        return `${bytes} B`
+    } else if(bytes < 1024 * 1024) {
+        return `${(bytes / 1024).toFixed(1)} KB`
+    }
+    return `${(bytes / (1024 * 1024)).toFixed(1)} MB`
+}
+
+export default function FileCard({ info, self, transfer, onAccept }) {
+    return (
+        <div className={`flex items-start gap-x-2.5 mt-5 ${self ? 'justify-end pr-5' : 'justify-start pl-5'} break-words mb-5`}>
+            <div className={`flex flex-col w-full max-w-[250px] leading-1.5 p-4 ${self ? "bg-primary rounded-s-xl rounded-br-xl" : "bg-[#454545] rounded-e-xl rounded-es-xl"}`}>
+                <span className="text-sm font-semibold text-white">{info.sender}</span>
+                <div className="flex items-center mt-2">
+                    <FaFileArrowDown className="text-white mr-2" size={20}/>
+                    <div className="flex flex-col">
+                        <span className="text-sm text-white">{info.name}</span>
+                        <span className="text-xs text-gray-300">{formatSize(info.size)}</span>
+                    </div>
+                </div>
+                {
+                    transfer && !transfer.saved && !transfer.failed &&
+                    <Progress size="sm" className="mt-2" value={transfer.size ? transfer.bytes / transfer.size * 100 : 100}/>
+                }
+                {
+                    transfer?.saved &&
+                    <span className="text-xs text-gray-300 mt-2">Saved to {transfer.saved}</span>
+                }
+                {
+                    transfer?.failed &&
+                    <span className="text-xs text-warning mt-2">Couldn't save the file</span>
+                }
+                {
+                    !self && !transfer &&
+                    <Button size="sm" color="primary" className="mt-2" onPress={onAccept}>
+                        Download
+                    </Button>
+                }
+            </div>
+        </div>
+    )
+}
@@ -0,0 +1,22 @@
+const COLORS = {
+    online: "bg-success",
+    typing: "bg-success",
+    idle: "bg-warning",
+}
+
+export default function MemberList({ members, username }) {
+    return (
+        <div className="flex flex-wrap gap-x-4 gap-y-1 w-[80vw] mt-2">
+            {
+                Object.values(members).map((member) => (
+                    <span key={member.username} className="flex items-center text-sm text-gray-300" title={`Safety number: ${member.fingerprint}`}>
+                        <span className={`inline-block size-2 rounded-full mr-1.5 ${COLORS[member.state] || "bg-gray-500"}`}></span>
+                        {member.username}
+                        {member.host && <span className="ml-1 text-xs text-secondary">host</span>}
+                        {member.username === username && <span className="ml-1 text-xs text-gray-500">you</span>}
+                    </span>
+                ))
+            }
+        </div>
+    )
+}